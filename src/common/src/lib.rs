@@ -1,15 +1,92 @@
 use ic_cdk::export::candid::{CandidType, Deserialize, Principal};
 use serde_bytes::ByteBuf;
+use sha2::Digest;
 use std::cmp::Eq;
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct Record {
     pub hash: Hash,
+    pub algorithm: HashAlgorithm,
+    pub digests: Vec<TaggedHash>,
     pub owner: Principal,
-    pub datum: Option<Datum>,
-    pub description: String,
+    /// The stored datum, whose content is the record's asset.
+    pub datum: Option<DatumInfo>,
     pub hidden: bool,
+    /// Every attestation of the hash, earliest (the priority date) first.
+    pub attestations: Vec<Attestation>,
+    /// The principals the owner lets read the record while it is hidden.
+    pub grants: Vec<Grant>,
+    /// When a hidden record is scheduled to be revealed.
+    pub reveal_at: Option<Timestamp>,
+    /// When the hidden record was revealed.
+    pub revealed: Option<Timestamp>,
+    /// Signed into capability links, bumped to revoke them all.
+    pub link_epoch: u32,
+    /// The owners before `owner`, the original notarizer first.
+    pub previous_owners: Vec<PreviousOwner>,
+    /// The recipient of a transfer offered by the owner.
+    pub transfer_to: Option<Principal>,
+    /// Set when the datum was deleted, the record stays as a tombstone.
+    pub takedown: Option<Takedown>,
+}
+
+impl Record {
+    /// The earliest attestation, which establishes the priority date.
+    pub fn priority(&self) -> &Attestation {
+        &self.attestations[0]
+    }
+
+    /// The attestations whose descriptions anyone may see: none while the
+    /// record is hidden, otherwise those which are not hidden themselves.
+    pub fn public_attestations(&self) -> impl Iterator<Item = &Attestation> {
+        let hidden = self.hidden;
+        self.attestations
+            .iter()
+            .filter(move |a| !hidden && !a.hidden)
+    }
+}
+
+/// Why and by whom the datum of a record was deleted.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct Takedown {
+    pub by: Principal,
+    pub at: Timestamp,
+    pub reason: String,
+}
+
+/// A former owner of a record, which transferred it at `until`.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct PreviousOwner {
+    pub owner: Principal,
+    pub until: Timestamp,
+}
+
+/// Read access to a hidden record and its datum, granted by the owner.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct Grant {
+    pub grantee: Principal,
+    pub granted: Timestamp,
+    /// The access lapses at this time, if given.
+    pub expires: Option<Timestamp>,
+}
+
+impl Grant {
+    pub fn is_active(&self, now: Timestamp) -> bool {
+        self.expires.is_none_or(|expires| now < expires)
+    }
+}
+
+/// A claim by `owner` that they held the hashed document at `created`.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct Attestation {
+    pub owner: Principal,
     pub created: Timestamp,
+    pub description: String,
+    pub hidden: bool,
+    /// Salts the certified `description_hash`, so that a redacted description
+    /// can't be guessed from it.  Empty for attestations of the first
+    /// release, whose descriptions were all searchable.
+    pub salt: ByteBuf,
 }
 
 #[derive(Default, Clone, Debug, CandidType, Deserialize)]
@@ -18,16 +95,384 @@ pub struct Datum {
     pub content: ByteBuf,
 }
 
+/// How much the chunk store saves by sharing chunks: `referenced_bytes` would
+/// be stored without it.
+#[derive(Clone, Debug, Default, PartialEq, Eq, CandidType, Deserialize)]
+pub struct StorageStats {
+    pub chunks: u64,
+    pub stored_bytes: u64,
+    /// The uses of the chunks.
+    pub references: u64,
+    pub referenced_bytes: u64,
+}
+
+/// A stored datum without its content, which is kept with the assets.
+#[derive(Default, Clone, Debug, CandidType, Deserialize)]
+pub struct DatumInfo {
+    pub content_type: String,
+}
+
 pub type Hash = String;
 pub type Timestamp = u64;
 pub type SearchTerms = String;
+pub type UploadId = u64;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, CandidType, Deserialize)]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    Sha512,
+    Sha3_256,
+    Blake3,
+}
+
+impl HashAlgorithm {
+    pub const ALL: &'static [HashAlgorithm] = &[
+        HashAlgorithm::Sha256,
+        HashAlgorithm::Sha512,
+        HashAlgorithm::Sha3_256,
+        HashAlgorithm::Blake3,
+    ];
+
+    pub fn digest_length(self) -> usize {
+        match self {
+            HashAlgorithm::Sha512 => 64,
+            _ => 32,
+        }
+    }
+
+    /// A stable tag for the algorithm, used in the certified record leaves.
+    pub fn tag(self) -> u8 {
+        match self {
+            HashAlgorithm::Sha256 => 0,
+            HashAlgorithm::Sha512 => 1,
+            HashAlgorithm::Sha3_256 => 2,
+            HashAlgorithm::Blake3 => 3,
+        }
+    }
+
+    pub fn hasher(self) -> Hasher {
+        match self {
+            HashAlgorithm::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+            HashAlgorithm::Sha512 => Hasher::Sha512(sha2::Sha512::new()),
+            HashAlgorithm::Sha3_256 => Hasher::Sha3_256(sha3::Sha3_256::new()),
+            HashAlgorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+
+    /// Computes the digest of the concatenation of `chunks`.
+    pub fn digest_chunks<'a, I: IntoIterator<Item = &'a [u8]>>(self, chunks: I) -> Vec<u8> {
+        let mut hasher = self.hasher();
+        for chunk in chunks {
+            hasher.update(chunk);
+        }
+        hasher.finalize()
+    }
+
+    pub fn digest(self, bytes: &[u8]) -> Vec<u8> {
+        self.digest_chunks(std::iter::once(bytes))
+    }
+}
+
+/// Computes a digest of content given in pieces, see `HashAlgorithm::hasher`.
+#[derive(Clone)]
+pub enum Hasher {
+    Sha256(sha2::Sha256),
+    Sha512(sha2::Sha512),
+    Sha3_256(sha3::Sha3_256),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    pub fn update(&mut self, bytes: &[u8]) {
+        match self {
+            Hasher::Sha256(hash) => hash.update(bytes),
+            Hasher::Sha512(hash) => hash.update(bytes),
+            Hasher::Sha3_256(hash) => hash.update(bytes),
+            Hasher::Blake3(hash) => {
+                hash.update(bytes);
+            }
+        }
+    }
+
+    pub fn finalize(self) -> Vec<u8> {
+        match self {
+            Hasher::Sha256(hash) => hash.finalize().to_vec(),
+            Hasher::Sha512(hash) => hash.finalize().to_vec(),
+            Hasher::Sha3_256(hash) => hash.finalize().to_vec(),
+            Hasher::Blake3(hash) => hash.finalize().as_bytes().to_vec(),
+        }
+    }
+}
+
+/// A hex encoded digest with the algorithm which produced it.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct TaggedHash {
+    pub algorithm: HashAlgorithm,
+    pub hash: Hash,
+}
 
 #[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
 pub struct RecordResult {
     pub hash: Hash,
+    pub algorithm: HashAlgorithm,
+    pub digests: Vec<TaggedHash>,
     pub owner: Principal,
     pub has_datum: bool,
+    /// The description of the priority attestation, empty if it is hidden
+    /// from the caller.
     pub description: String,
     pub hidden: bool,
+    /// The priority date: when the hash was first attested.
+    pub created: Timestamp,
+    pub attestations: Vec<AttestationResult>,
+    /// The grants of the record, only shown to its owner.
+    pub grants: Vec<Grant>,
+    pub reveal_at: Option<Timestamp>,
+    /// When the hidden record was revealed, this is certified.
+    pub revealed: Option<Timestamp>,
+    /// Who notarized the record, the first of `previous_owners` or else the
+    /// current owner.
+    pub original_owner: Principal,
+    pub previous_owners: Vec<PreviousOwner>,
+    /// A pending transfer, only shown to the owner and the recipient.
+    pub transfer_to: Option<Principal>,
+    /// The takedown time is certified.
+    pub takedown: Option<Takedown>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct AttestationResult {
+    pub owner: Principal,
     pub created: Timestamp,
+    /// Empty if the attestation is hidden from the caller.
+    pub description: String,
+    /// The hex `description_hash` of the description, which is certified even
+    /// when the description is redacted.
+    pub description_hash: String,
+    /// The hex salt of `description_hash`, empty with a redacted description.
+    pub salt: String,
+    pub hidden: bool,
+    /// Set on the earliest attestation, which establishes the priority date.
+    pub priority: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum RecordEventKind {
+    Created,
+    Attested,
+    Revealed,
+    Transferred,
+    Deleted,
+}
+
+/// An entry in the append-only change feed.  `sequence` numbers are dense
+/// and start at 0.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct RecordEvent {
+    pub sequence: u64,
+    pub hash: Hash,
+    pub kind: RecordEventKind,
+    pub principal: Principal,
+    pub timestamp: Timestamp,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum AdminEventKind {
+    ClearRequested,
+    ClearCancelled,
+    /// The records were cleared, after a snapshot.
+    ClearConfirmed,
+    /// The snapshot of the last clear was restored.
+    ClearUndone,
+    /// The snapshot of the last clear passed its retention window.
+    SnapshotExpired,
+}
+
+/// An entry in the append-only log of administrative operations.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct AdminEvent {
+    pub kind: AdminEventKind,
+    pub principal: Principal,
+    pub timestamp: Timestamp,
+}
+
+/// A requested clear, which `confirm_clear` carries out given the `nonce`.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct PendingClear {
+    pub nonce: String,
+    pub requested_by: Principal,
+    pub requested: Timestamp,
+    /// When the requester may confirm it, any other admin may right away.
+    pub confirmable: Timestamp,
+    pub expires: Timestamp,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, CandidType, Deserialize)]
+pub struct ClearStatus {
+    pub pending: Option<PendingClear>,
+    /// Until when the last clear can be undone, if it can.
+    pub undo_until: Option<Timestamp>,
+}
+
+/// An event with the current state of its record, if it still exists.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct UpdatedRecordResult {
+    pub event: RecordEvent,
+    pub record: Option<RecordResult>,
+}
+
+/// A page of the change feed.  Pass `next_cursor` to get the next page, it is
+/// equal to the cursor passed in when there are no new events.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct UpdatesPage {
+    pub updates: Vec<UpdatedRecordResult>,
+    pub next_cursor: u64,
+}
+
+/// The order of search results and `get_data` pages.  `Relevance` falls back
+/// to `CreatedDescending` for queries without search terms.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum SortOrder {
+    Relevance,
+    CreatedAscending,
+    CreatedDescending,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, CandidType, Deserialize)]
+pub struct SearchRequest {
+    pub query: SearchTerms,
+    pub sort: Option<SortOrder>,
+    /// The `next_cursor` of the previous page, none for the first page.
+    pub cursor: Option<String>,
+    pub limit: Option<u32>,
+}
+
+/// A page of search results.  `next_cursor` is none on the last page and
+/// `total` is the number of matching records at the time of the call, which
+/// can change between pages.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct SearchPage {
+    pub results: Vec<RecordResult>,
+    pub next_cursor: Option<String>,
+    pub total: u64,
+}
+
+/// What an authorized principal may do, besides what any caller may.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, CandidType, Deserialize)]
+pub enum Role {
+    /// Everything, including managing the authorized principals and clearing
+    /// the notary.
+    Admin,
+    /// Sees hidden records and takes down datums.
+    Moderator,
+    /// Sees hidden records, exports the records and lists the authorized
+    /// principals, but changes nothing.
+    Auditor,
+    /// Manages the static assets of the frontend.
+    Uploader,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct AuthorizedPrincipal {
+    pub principal: Principal,
+    pub role: Role,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum NotaryError {
+    InvalidHash,
+    DescriptionTooLong,
+    AlreadyExists,
+    NotOwner,
+    NotFound,
+    Unauthorized,
+    QuotaExceeded,
+    /// The search query could not be parsed, with the reason.
+    InvalidQuery(String),
+    /// The indexes are still being rebuilt after an upgrade, retry later.
+    Rebuilding,
+}
+
+pub type NotaryResult<T> = Result<T, NotaryError>;
+
+/// A record together with the proof that the canister certified it: the IC
+/// `certificate` for the canister's certified data and the CBOR encoded
+/// `witness` (a pruned hash tree) of the record's leaf under `records`.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Receipt {
+    pub record: RecordResult,
+    pub certificate: ByteBuf,
+    pub witness: ByteBuf,
+}
+
+/// Domain separator for the record leaves in the certified tree.
+const RECORD_LEAF_DOMAIN: &[u8] = b"dfnhack7-record";
+
+/// The hash of an attestation, as committed to by the record leaf, given the
+/// `description_hash` of its description.
+pub fn attestation_leaf_hash(
+    owner: &Principal,
+    created: Timestamp,
+    description_hash: &[u8; 32],
+    hidden: bool,
+) -> [u8; 32] {
+    let owner = owner.as_slice();
+    let mut hash = sha2::Sha256::new();
+    hash.update((owner.len() as u32).to_be_bytes());
+    hash.update(owner);
+    hash.update(created.to_be_bytes());
+    hash.update(description_hash);
+    hash.update([hidden as u8]);
+    hash.finalize().into()
+}
+
+/// The SHA-256 of the length prefixed `salt` and the description.
+pub fn description_hash(salt: &[u8], description: &str) -> [u8; 32] {
+    let mut hash = sha2::Sha256::new();
+    hash.update((salt.len() as u32).to_be_bytes());
+    hash.update(salt);
+    hash.update(description.as_bytes());
+    hash.finalize().into()
+}
+
+/// The value stored in the certified `records` tree for a record, given the
+/// `attestation_leaf_hash` of each attestation in order.
+pub fn record_leaf_hash(
+    algorithm: HashAlgorithm,
+    owner: &Principal,
+    previous_owners: &[PreviousOwner],
+    hidden: bool,
+    revealed: Option<Timestamp>,
+    taken_down: Option<Timestamp>,
+    attestations: &[[u8; 32]],
+) -> [u8; 32] {
+    let owner = owner.as_slice();
+    let mut hash = sha2::Sha256::new();
+    hash.update(RECORD_LEAF_DOMAIN);
+    hash.update([algorithm.tag()]);
+    hash.update((owner.len() as u32).to_be_bytes());
+    hash.update(owner);
+    hash.update((previous_owners.len() as u32).to_be_bytes());
+    for previous in previous_owners {
+        let owner = previous.owner.as_slice();
+        hash.update((owner.len() as u32).to_be_bytes());
+        hash.update(owner);
+        hash.update(previous.until.to_be_bytes());
+    }
+    hash.update([hidden as u8]);
+    for at in [revealed, taken_down].iter() {
+        match at {
+            Some(at) => {
+                hash.update([1]);
+                hash.update(at.to_be_bytes());
+            }
+            None => hash.update([0]),
+        }
+    }
+    hash.update((attestations.len() as u32).to_be_bytes());
+    for attestation in attestations {
+        hash.update(attestation);
+    }
+    hash.finalize().into()
 }
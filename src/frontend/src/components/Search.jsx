@@ -3,15 +3,33 @@ import { useActor } from "./ActorProvider";
 import { SearchResult, SearchResults } from "./SearchResults";
 import { Form, Button, Box, Columns, Block } from "react-bulma-components";
 
+const LINK_VALIDITY_MS = 7 * 24 * 60 * 60 * 1000;
+
 export const Search = ({ onSubmit, principalId }) => {
   const [term, setTerm] = useState("");
   const [results, setResults] = useState(null);
+  const [error, setError] = useState(null);
+  const [nextCursor, setNextCursor] = useState(null);
+  const [total, setTotal] = useState(0);
   const [revealing, setRevealing] = useState(false);
   const { actor } = useActor();
 
-  const search = async (term) => {
-    let results = await actor.search(term);
-    setResults(results);
+  const search = async (term, cursor = null) => {
+    let reply = await actor.search({
+      query: term,
+      sort: [],
+      cursor: cursor ? [cursor] : [],
+      limit: [],
+    });
+    if ("Err" in reply) {
+      // Partial queries, e.g. an unterminated quote, are common while typing.
+      setError(reply.Err.InvalidQuery || "The search failed.");
+      return;
+    }
+    setError(null);
+    setResults(cursor ? results.concat(reply.Ok.results) : reply.Ok.results);
+    setNextCursor(reply.Ok.next_cursor[0] || null);
+    setTotal(Number(reply.Ok.total));
   };
 
   const makePublic = async (result) => {
@@ -23,13 +41,26 @@ export const Search = ({ onSubmit, principalId }) => {
     }
   };
 
+  // Copies a link to a hidden datum which works for a week.
+  const shareLink = async (result, url) => {
+    const expires = BigInt(Date.now() + LINK_VALIDITY_MS) * 1000000n;
+    const reply = await actor.create_link(result.hash, expires);
+    if ("Err" in reply) {
+      window.alert("The link could not be created.");
+      return;
+    }
+    const link = url + "?token=" + reply.Ok;
+    await navigator.clipboard.writeText(link);
+    window.alert("Link copied, it is valid for a week.");
+  };
+
   return (
     <Columns mt="6">
       <Box style={{ width: 700, margin: "auto" }}>
         <Form.Field kind="addons" size="large">
           <Form.Control fullwidth={true}>
             <Form.Input
-              placeholder="Search in description, hash, principals..."
+              placeholder='Search in description, hash, or filter e.g. owner:... type:application/pdf after:2026-01-01 "exact phrase"'
               value={term}
               onChange={(e) => {
                 setTerm(e.target.value);
@@ -44,23 +75,36 @@ export const Search = ({ onSubmit, principalId }) => {
           </Form.Control>
         </Form.Field>
 
-        {results && results.length > 0 && (
+        {error && (
+          <Block my="4" textAlign="center" textColor="danger">
+            {error}
+          </Block>
+        )}
+        {!error && results && results.length > 0 && (
           <>
             <Block my="4" textWeight="bold" px="1">
-              {results.length} result(s)
+              {total} result(s)
             </Block>
             {results.map((result, index) => (
               <SearchResult
                 result={result}
                 key={index}
                 makePublic={makePublic}
+                shareLink={shareLink}
                 revealing={revealing === result.hash}
                 principalId={principalId}
               ></SearchResult>
             ))}
+            {nextCursor && (
+              <Block textAlign="center">
+                <Button onClick={() => search(term, nextCursor)}>
+                  Load more
+                </Button>
+              </Block>
+            )}
           </>
         )}
-        {results && results.length === 0 && (
+        {!error && results && results.length === 0 && (
           <Block my="4" textAlign="center" textWeight="bold">
             No results for {term}
           </Block>
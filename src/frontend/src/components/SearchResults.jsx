@@ -11,6 +11,7 @@ const FieldLabel = ({ children }) => {
 export const SearchResult = ({
   result,
   makePublic,
+  shareLink,
   revealing,
   principalId,
 }) => {
@@ -46,21 +47,44 @@ export const SearchResult = ({
     >
       <div style={{ flex: 1 }}>
         <Heading size="6" mb="1">
-          {result.description}
+          {result.description || (result.hidden && <i>Hidden</i>)}
         </Heading>
         <div style={{ fontSize: "12px" }}>
           <div>
-            <FieldLabel>Added on: </FieldLabel>
+            <FieldLabel>Priority date: </FieldLabel>
             {new Date(Number(result.created / 1000000n)).toLocaleString()}
           </div>
           <div>
             <FieldLabel>Content: </FieldLabel>
-            {result_link} {!result.has_datum ? "(hash only)" : ""}
+            {result_link}{" "}
+            {result.takedown.length > 0
+              ? "(taken down: " + result.takedown[0].reason + ")"
+              : !result.has_datum
+              ? "(hash only)"
+              : ""}
+            {url && result.hidden && (
+              <a onClick={() => shareLink(result, url)}> (share link)</a>
+            )}
           </div>
           <div>
             <FieldLabel>Added by: </FieldLabel>
-            {result.owner.toString()}
+            {result.original_owner.toString()}
           </div>
+          {result.previous_owners.length > 0 && (
+            <div>
+              <FieldLabel>Owned by: </FieldLabel>
+              {result.owner.toString()}
+            </div>
+          )}
+          {result.attestations.slice(1).map((attestation, index) => (
+            <div key={index}>
+              <FieldLabel>Attested by: </FieldLabel>
+              {attestation.owner.toString()} on{" "}
+              {new Date(
+                Number(attestation.created / 1000000n)
+              ).toLocaleString()}
+            </div>
+          ))}
         </div>
       </div>
       {result.hidden && result.owner.toString() === principalId && (
@@ -11,11 +11,52 @@ import {
   Columns,
 } from "react-bulma-components";
 
+// Files larger than this are sent with the chunked upload protocol.
+const CHUNK_SIZE = 1024 * 1024;
+
+const ERROR_MESSAGES = {
+  InvalidHash: "The hash is not valid",
+  DescriptionTooLong: "The description is too long",
+  AlreadyExists: "You already notarized this",
+  NotOwner: "You do not own this upload",
+  NotFound: "Not found",
+  Unauthorized: "You are not authorized",
+  QuotaExceeded: "The file is too large or too many uploads are in progress",
+};
+
+function errorMessage(err) {
+  return ERROR_MESSAGES[Object.keys(err)[0]] || "An error occurred.";
+}
+
+async function uploadChunked(actor, file, note, isPrivate, revealAt) {
+  const content = new Uint8Array(await file.arrayBuffer());
+  const digest = new Uint8Array(await crypto.subtle.digest("SHA-256", content));
+  const hash = Array.from(digest)
+    .map((b) => b.toString(16).padStart(2, "0"))
+    .join("");
+  const begin = await actor.begin_upload(file.type, note, isPrivate, revealAt);
+  if ("Err" in begin) {
+    return begin;
+  }
+  const uploadId = begin.Ok;
+  for (let start = 0; start < content.length; start += CHUNK_SIZE) {
+    const result = await actor.upload_chunk(
+      uploadId,
+      Array.from(content.subarray(start, start + CHUNK_SIZE))
+    );
+    if ("Err" in result) {
+      return result;
+    }
+  }
+  return await actor.commit_upload(uploadId, hash);
+}
+
 export const Upload = ({ principal }) => {
   const [file, setFile] = useState(null);
   const [note, setNote] = useState("");
   const [hash, setHash] = useState("");
   const [isPrivate, setIsPrivate] = useState(false);
+  const [revealDate, setRevealDate] = useState("");
   const [uploading, setUploading] = useState(false);
   const [error, setError] = useState(false);
   const [success, setSuccess] = useState(false);
@@ -32,21 +73,32 @@ export const Upload = ({ principal }) => {
     setUploading(true);
     setSuccess(false);
     setError(false);
+    // Nanoseconds since the epoch, as an optional Candid value.
+    const revealAt =
+      isPrivate && revealDate
+        ? [BigInt(new Date(revealDate).getTime()) * 1000000n]
+        : [];
     try {
       switch (uploadType) {
         case "file":
           {
-            const result = await actor.notarize(
-              {
-                content: Array.from(new Uint8Array(await file.arrayBuffer())),
-                content_type: file.type,
-              },
-              note,
-              isPrivate
-            );
+            const result =
+              file.size > CHUNK_SIZE
+                ? await uploadChunked(actor, file, note, isPrivate, revealAt)
+                : await actor.notarize(
+                    {
+                      content: Array.from(
+                        new Uint8Array(await file.arrayBuffer())
+                      ),
+                      content_type: file.type,
+                    },
+                    note,
+                    isPrivate,
+                    revealAt
+                  );
 
-            if (result.length === 0) {
-              setError("This file was already notarized");
+            if ("Err" in result) {
+              setError(errorMessage(result.Err));
             } else {
               setSuccess(true);
             }
@@ -54,14 +106,15 @@ export const Upload = ({ principal }) => {
             inputFileRef.current.value = null;
             setNote("");
             setIsPrivate(false);
+            setRevealDate("");
           }
           break;
         case "hash":
           {
-            const result = await actor.notarize_hash(hash, note);
+            const result = await actor.notarize_hash(hash, note, []);
 
-            if (result.length === 0) {
-              setError("This hash was already added");
+            if ("Err" in result) {
+              setError(errorMessage(result.Err));
             } else {
               setSuccess(true);
             }
@@ -190,6 +243,19 @@ export const Upload = ({ principal }) => {
                   </Form.Checkbox>
                 </Form.Control>
               </Form.Field>
+              {isPrivate && (
+                <Form.Field>
+                  <Form.Label size="small">Reveal automatically on</Form.Label>
+                  <Form.Control>
+                    <Form.Input
+                      type="datetime-local"
+                      value={revealDate}
+                      disabled={uploading}
+                      onChange={(e) => setRevealDate(e.target.value)}
+                    />
+                  </Form.Control>
+                </Form.Field>
+              )}
               <Form.Field>
                 <Form.Control>
                   <Button disabled={uploading} color="primary" outlined={true}>
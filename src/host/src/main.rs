@@ -1,8 +1,10 @@
 #[macro_use]
 extern crate log;
 extern crate serde_json;
+use anyhow::{anyhow, Context};
 use candid::{Decode, Encode};
-use dfnhack7_common;
+use dfnhack7_common::{self, NotaryResult, RecordResult, UpdatesPage};
+use dfnhack7_verify::IC_ROOT_KEY;
 use dotenv::dotenv;
 use hyper::{
     header,
@@ -16,9 +18,7 @@ use ic_types::Principal;
 use std::collections::HashMap;
 use std::convert::Infallible;
 use std::sync::{Arc, Mutex};
-use std::time::{SystemTime, UNIX_EPOCH};
 
-const IC_ROOT_KEY: &[u8; 133] = b"\x30\x81\x82\x30\x1d\x06\x0d\x2b\x06\x01\x04\x01\x82\xdc\x7c\x05\x03\x01\x02\x01\x06\x0c\x2b\x06\x01\x04\x01\x82\xdc\x7c\x05\x03\x02\x01\x03\x61\x00\x81\x4c\x0e\x6e\xc7\x1f\xab\x58\x3b\x08\xbd\x81\x37\x3c\x25\x5c\x3c\x37\x1b\x2e\x84\x86\x3c\x98\xa4\xf1\xe0\x8b\x74\x23\x5d\x14\xfb\x5d\x9c\x0c\xd5\x46\xd9\x68\x5f\x91\x3a\x0c\x0b\x2c\xc5\x34\x15\x83\xbf\x4b\x43\x92\xe4\x67\xdb\x96\xd6\x5b\x9b\xb4\xcb\x71\x71\x12\xf8\x47\x2e\x0d\x5a\x4d\x14\x50\x5f\xfd\x74\x84\xb0\x12\x91\x09\x1c\x5f\x87\xb9\x88\x83\x46\x3f\x98\x09\x1a\x0b\xaa\xae";
 const CANISTER_URL_TEMPLATE: &str = "https://{}.ic0.app";
 
 gflags::define! {
@@ -30,22 +30,45 @@ gflags::define! {
 gflags::define! {
     --get-root-key = false
 }
+gflags::define! {
+    /// Fetch the receipt for this hash and save it to <hash>.receipt.
+    --save-receipt: &str
+}
+gflags::define! {
+    /// Verify the receipt in this file offline and exit.
+    --verify-receipt: &str
+}
+gflags::define! {
+    /// The file the receipt given by --verify-receipt should be for.
+    --verify-file: &str
+}
 
+/// A mirror of the canister's records, kept up to date from the change feed.
 struct State {
-    data: HashMap<String, String>,
-    checked: u64,
+    data: HashMap<String, RecordResult>,
+    cursor: u64,
 }
 
 #[tokio::main]
-async fn main() -> () {
+async fn main() {
     dotenv().ok();
     pretty_env_logger::init();
     let _args = gflags::parse();
-    if !PORT.is_present() || PORT.flag == 0 {
+    if VERIFY_RECEIPT.is_present() {
+        match verify_receipt() {
+            Ok(true) => std::process::exit(0),
+            Ok(false) => std::process::exit(1),
+            Err(e) => {
+                error!("{:#}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    if !SAVE_RECEIPT.is_present() && (!PORT.is_present() || PORT.flag == 0) {
         error!("port flag missing or empty");
         std::process::exit(1);
     }
-    if !CANISTER_ID.is_present() || CANISTER_ID.flag == "" {
+    if !CANISTER_ID.is_present() || CANISTER_ID.flag.is_empty() {
         error!("canister_id flag missing or empty: {}", CANISTER_ID.flag);
         std::process::exit(1);
     }
@@ -63,9 +86,16 @@ async fn main() -> () {
             .set_root_key(IC_ROOT_KEY.to_vec())
             .expect("set root key");
     };
+    if SAVE_RECEIPT.is_present() {
+        if let Err(e) = save_receipt(&agent, SAVE_RECEIPT.flag).await {
+            error!("{:#}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
     let state: Arc<Mutex<State>> = Arc::new(Mutex::new(State {
         data: HashMap::new(),
-        checked: 0,
+        cursor: 0,
     }));
     update_data(state.clone(), agent.clone()).await;
 
@@ -107,17 +137,21 @@ async fn serve_data(
     state: Arc<Mutex<State>>,
 ) -> Result<Response<Body>, Infallible> {
     let path = req.uri().path();
-    if path == "" {
+    if path.is_empty() {
         return redirect(&canister_uri);
     }
     let path = &path[1..path.len()];
-    if path == "" {
+    if path.is_empty() {
         return redirect(&canister_uri);
     }
     match state.lock().unwrap().data.get(path) {
         Some(r) => {
-            eprintln!("data {} to {}", path, r);
-            let response = Response::new(Body::from(r.to_string()));
+            eprintln!("data {}", path);
+            let mut response = Response::new(Body::from(record_json(r).to_string()));
+            response.headers_mut().insert(
+                header::CONTENT_TYPE,
+                header::HeaderValue::from_static("application/json"),
+            );
             Ok(response)
         }
         None => {
@@ -135,34 +169,138 @@ fn redirect(uri: &str) -> Result<Response<Body>, Infallible> {
         .unwrap())
 }
 
+fn record_json(r: &RecordResult) -> serde_json::Value {
+    serde_json::json!({
+        "hash": r.hash,
+        "owner": r.owner.to_string(),
+        "original_owner": r.original_owner.to_string(),
+        "previous_owners": r.previous_owners.iter().map(|p| {
+            serde_json::json!({"owner": p.owner.to_string(), "until": p.until})
+        }).collect::<Vec<_>>(),
+        "has_datum": r.has_datum,
+        "takedown": r.takedown.as_ref().map(|t| {
+            serde_json::json!({"by": t.by.to_string(), "at": t.at, "reason": t.reason})
+        }),
+        "description": r.description,
+        "hidden": r.hidden,
+        "created": r.created,
+        "digests": r.digests.iter().map(|d| {
+            serde_json::json!({"algorithm": format!("{:?}", d.algorithm), "hash": d.hash})
+        }).collect::<Vec<_>>(),
+        "attestations": r.attestations.iter().map(|a| {
+            serde_json::json!({
+                "owner": a.owner.to_string(),
+                "created": a.created,
+                "description": a.description,
+                "description_hash": a.description_hash,
+                "salt": a.salt,
+                "hidden": a.hidden,
+                "priority": a.priority,
+            })
+        }).collect::<Vec<_>>(),
+    })
+}
+
+/// Fetches the page of the change feed at `cursor`.
+async fn get_updates(agent: &Agent, cursor: u64) -> anyhow::Result<UpdatesPage> {
+    let canister_id = Principal::from_text(CANISTER_ID.flag)?;
+    let response = agent
+        .query(&canister_id, "get_updates")
+        .with_arg(Encode!(&cursor, &None::<u32>)?)
+        .call()
+        .await?;
+    Decode!(response.as_slice(), NotaryResult<UpdatesPage>)
+        .context("malformed reply")?
+        .map_err(|e| anyhow!("{:?}", e))
+}
+
+/// Applies the change feed from the last cursor on.
 async fn update_data(state: Arc<Mutex<State>>, agent: Agent) {
-    let waiter = delay::Delay::builder()
-        .throttle(std::time::Duration::from_millis(500))
-        .timeout(std::time::Duration::from_secs(60))
-        .build();
-    let now = SystemTime::now();
-    let since_the_epoch = now.duration_since(UNIX_EPOCH).expect("Time went backwards");
-    let canister_id = Principal::from_text(CANISTER_ID.flag).expect("Principal::from_text");
-    let checked = Encode!(&state.lock().unwrap().checked).unwrap();
+    loop {
+        let cursor = state.lock().unwrap().cursor;
+        let page = match get_updates(&agent, cursor).await {
+            Ok(page) => page,
+            Err(e) => {
+                error!("get_updates failed: {:#}", e);
+                return;
+            }
+        };
+        // Pages can be empty when they only have events of hidden records.
+        if page.next_cursor == cursor {
+            return;
+        }
+        let mut state = state.lock().unwrap();
+        for update in page.updates {
+            match update.record {
+                Some(record) => {
+                    state.data.insert(update.event.hash, record);
+                }
+                None => {
+                    state.data.remove(&update.event.hash);
+                }
+            }
+        }
+        state.cursor = page.next_cursor;
+    }
+}
+
+async fn save_receipt(agent: &Agent, hash: &str) -> anyhow::Result<()> {
+    let canister_id = Principal::from_text(CANISTER_ID.flag)?;
     let response = agent
-        .update(&canister_id, "get_updated_links")
-        .with_arg(&checked)
-        .call_and_wait(waiter)
+        .query(&canister_id, "get_receipt")
+        .with_arg(Encode!(&hash.to_string())?)
+        .call()
         .await
-        .expect("response");
-    let result = Decode!(
+        .context("get_receipt failed")?;
+    let receipt = Decode!(
         response.as_slice(),
-        Vec<dfnhack7_common::UpdatedRecordResult>
+        dfnhack7_common::NotaryResult<dfnhack7_common::Receipt>
     )
-    .expect("result");
-    {
-        let mut state = state.lock().unwrap();
-        for r in result {
-            if let Some(canister_id) = r.canister_id {
-                state.data.insert(r.datum, canister_id);
-            }
+    .context("malformed reply")?
+    .map_err(|e| anyhow!("no receipt for {}: {:?}", hash, e))?;
+    let path = format!("{}.receipt", hash);
+    std::fs::write(&path, Encode!(&receipt)?).context("write receipt")?;
+    println!("saved receipt to {}", path);
+    Ok(())
+}
+
+/// Verifies the receipt file offline, returning whether it is valid.
+fn verify_receipt() -> anyhow::Result<bool> {
+    if !CANISTER_ID.is_present() || CANISTER_ID.flag.is_empty() {
+        return Err(anyhow!(
+            "canister_id flag missing or empty: {}",
+            CANISTER_ID.flag
+        ));
+    }
+    let canister_id = Principal::from_text(CANISTER_ID.flag)?;
+    let bytes = std::fs::read(VERIFY_RECEIPT.flag).context("read receipt")?;
+    let receipt =
+        Decode!(bytes.as_slice(), dfnhack7_common::Receipt).context("malformed receipt")?;
+    let content = if VERIFY_FILE.is_present() {
+        Some(std::fs::read(VERIFY_FILE.flag).context("read file")?)
+    } else {
+        None
+    };
+    match dfnhack7_verify::verify_receipt(
+        &receipt,
+        canister_id.as_slice(),
+        IC_ROOT_KEY,
+        content.as_deref(),
+    ) {
+        Ok(verified) => {
+            println!(
+                "valid: {} notarized by {} at {}, owned by {} (certified at {})",
+                receipt.record.hash,
+                receipt.record.original_owner,
+                receipt.record.created,
+                receipt.record.owner,
+                verified.certified_at
+            );
+            Ok(true)
+        }
+        Err(e) => {
+            println!("invalid: {}", e);
+            Ok(false)
         }
-        // Play it safe for clock skew.
-        state.checked = (since_the_epoch - std::time::Duration::from_secs(10)).as_nanos() as u64;
     }
 }
@@ -1,14 +1,17 @@
+use crate::btree::{MapId, StableMap};
 use crate::rc_bytes::RcBytes;
+use crate::stable::Blob;
+use dfnhack7_common::{AuthorizedPrincipal, NotaryError, NotaryResult, Role};
 use ic_cdk::api::{caller, data_certificate, set_certified_data, time, trap};
-use ic_cdk::export::candid::{CandidType, Deserialize, Func, Nat, Principal};
-use ic_cdk_macros::update;
-use ic_certified_map::{AsHashTree, Hash, HashTree, RbTree};
+use ic_cdk::export::candid::{CandidType, Deserialize, Func, Int, Nat, Principal};
+use ic_cdk_macros::{query, update};
+pub use ic_certified_map::Hash;
+use ic_certified_map::{AsHashTree, HashTree, RbTree};
 use num_traits::ToPrimitive;
-use serde::Serialize;
 use serde_bytes::ByteBuf;
 use sha2::Digest;
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 
 /// The order in which we pick encodings for certification.
 const ENCODING_CERTIFICATION_ORDER: &[&str] = &["identity", "gzip", "compress", "deflate", "br"];
@@ -16,6 +19,28 @@ const ENCODING_CERTIFICATION_ORDER: &[&str] = &["identity", "gzip", "compress",
 /// The file to serve if the requested file wasn't found.
 const INDEX_FILE: &str = "/index.html";
 
+/// The size above which the stored chunks of the content are returned in
+/// separate responses, which must stay well below the response size limit.
+const STREAMING_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// How long a batch of the asset interface may stay uncommitted after its
+/// last chunk.
+const BATCH_EXPIRY_NANOS: u64 = 5 * 60 * 1_000_000_000;
+
+/// The encodings stored besides identity for compressible content.
+const COMPRESSED_ENCODINGS: &[&str] = &["gzip", "deflate"];
+
+/// Smaller content isn't worth compressing, larger content would hold up the
+/// queue for too many heartbeats.
+const MIN_COMPRESSIBLE_SIZE: usize = 1024;
+const MAX_COMPRESSIBLE_SIZE: usize = 8 * 1024 * 1024;
+
+/// How much content a step of the compression compresses, well within the
+/// instruction limit of a message.
+const COMPRESSION_SLICE_SIZE: usize = 256 * 1024;
+/// How many steps in a row may fail before the asset is left uncompressed.
+const MAX_COMPRESSION_ATTEMPTS: u32 = 3;
+
 thread_local! {
     static STATE: State = State::default();
     static ASSET_HASHES: RefCell<AssetHashes> = RefCell::new(RbTree::new());
@@ -23,31 +48,323 @@ thread_local! {
 
 type AssetHashes = RbTree<Key, Hash>;
 
+/// The assets, whose content is in the chunk store, see `crate::chunks`.
+const ASSETS: StableMap<Key, Asset> = StableMap::new(MapId::Assets);
+/// The assets removed by the last clear, see `crate::Snapshot`.
+const SNAPSHOT_ASSETS: StableMap<Key, Asset> = StableMap::new(MapId::SnapshotAssets);
+
 #[derive(Default)]
 struct State {
-    assets: RefCell<HashMap<Key, Asset>>,
-    authorized: RefCell<Vec<Principal>>,
+    authorized: RefCell<HashMap<Principal, Role>>,
+    batches: RefCell<HashMap<u64, Batch>>,
+    upload_chunks: RefCell<HashMap<u64, UploadChunk>>,
+    next_batch_id: RefCell<u64>,
+    next_chunk_id: RefCell<u64>,
+    /// The keys of the assets to compress from the heartbeat, which is
+    /// rebuilt on upgrade.
+    compress_queue: RefCell<VecDeque<Key>>,
+    /// The asset being compressed, which is restarted after an upgrade.
+    compressing: RefCell<Option<Compression>>,
+    /// Whether the heartbeat called `compress_next` and awaits its reply.
+    compress_in_flight: RefCell<bool>,
+    /// After an upgrade, the key of the next asset to certify, see
+    /// `rebuild_assets`.
+    rebuild: RefCell<Option<Key>>,
 }
 
-#[derive(Clone, Debug, CandidType, Deserialize)]
+/// A batch of the asset interface, which isn't saved on upgrade.
+struct Batch {
+    expires: Timestamp,
+}
+
+struct UploadChunk {
+    batch_id: u64,
+    content: ByteBuf,
+}
+
+/// What is saved on upgrade, the assets stay in stable memory.
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
 pub struct StableState {
+    authorized: Vec<AuthorizedPrincipal>,
+}
+
+/// The state saved with `stable_save`, version 0 in `crate::upgrade`.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct StableStateV0 {
+    /// The principals authorized before roles, which become admins.
     authorized: Vec<Principal>,
-    stable_assets: HashMap<String, Asset>,
+    stable_assets: HashMap<String, AssetV0>,
 }
 
 #[derive(Default, Clone, Debug, CandidType, Deserialize)]
 struct AssetEncoding {
     modified: Timestamp,
-    content_chunks: Vec<RcBytes>,
+    /// The content, in chunks of the chunk store, see `crate::chunks`.
+    content_chunks: Vec<Blob>,
     total_length: usize,
     certified: bool,
     sha256: [u8; 32],
 }
 
+impl AssetEncoding {
+    fn free(&self) {
+        for blob in self.content_chunks.iter() {
+            crate::chunks::release(*blob);
+        }
+    }
+
+    /// Reads the chunks from `start` on, up to `STREAMING_CHUNK_SIZE` bytes
+    /// but at least one chunk, returning the index of the next chunk.
+    fn read_chunks(&self, start: usize) -> (RcBytes, usize) {
+        let mut body = vec![];
+        let mut next = start;
+        while let Some(blob) = self.content_chunks.get(next) {
+            if next > start && body.len() + blob.length as usize > STREAMING_CHUNK_SIZE {
+                break;
+            }
+            body.extend_from_slice(&crate::stable::get(*blob));
+            next += 1;
+        }
+        (RcBytes::from(ByteBuf::from(body)), next)
+    }
+
+    /// Reads the bytes from `start` up to `end` or the end of the content.
+    /// Only a range across chunks is copied.
+    fn read_range(&self, start: usize, end: usize) -> RcBytes {
+        let end = end.min(self.total_length);
+        let mut slices = vec![];
+        let mut offset = 0;
+        for blob in self.content_chunks.iter() {
+            let length = blob.length as usize;
+            if offset < end && offset + length > start {
+                let bytes = RcBytes::from(ByteBuf::from(crate::stable::get(*blob)));
+                slices.push(bytes.slice(start.saturating_sub(offset)..length.min(end - offset)));
+            }
+            offset += length;
+        }
+        match slices.len() {
+            1 => slices.remove(0),
+            _ => RcBytes::from(ByteBuf::from(
+                slices
+                    .iter()
+                    .flat_map(|slice| slice.iter().copied())
+                    .collect::<Vec<u8>>(),
+            )),
+        }
+    }
+}
+
 #[derive(Default, Clone, Debug, CandidType, Deserialize)]
-struct Asset {
+pub struct Asset {
     content_type: String,
     encodings: HashMap<String, AssetEncoding>,
+    /// Overrides the 200 status of the response, e.g. for a tombstone.
+    status_code: Option<u16>,
+}
+
+impl Asset {
+    fn free(&self) {
+        for enc in self.encodings.values() {
+            enc.free();
+        }
+    }
+
+    /// Whether the identity content should be compressed and isn't yet.  A
+    /// compression which didn't make it smaller is retried after an upgrade.
+    fn needs_compression(&self) -> bool {
+        self.status_code.is_none()
+            && is_compressible(&self.content_type)
+            && self.encodings.get("identity").is_some_and(|enc| {
+                (MIN_COMPRESSIBLE_SIZE..=MAX_COMPRESSIBLE_SIZE).contains(&enc.total_length)
+            })
+            && COMPRESSED_ENCODINGS
+                .iter()
+                .all(|enc_name| !self.encodings.contains_key(*enc_name))
+    }
+}
+
+/// Whether content of the type is usually text, which compresses well.
+fn is_compressible(content_type: &str) -> bool {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    mime.starts_with("text/")
+        || mime.ends_with("+xml")
+        || mime.ends_with("+json")
+        || matches!(
+            mime.as_str(),
+            "application/json" | "application/xml" | "application/javascript"
+        )
+}
+
+/// Compresses content for a `Content-Encoding`, in which "deflate" is the
+/// zlib format, a slice at a time.
+enum Compressor {
+    Gzip(libflate::gzip::Encoder<Vec<u8>>),
+    Deflate(libflate::zlib::Encoder<Vec<u8>>),
+}
+
+impl Compressor {
+    fn new(enc_name: &str) -> std::io::Result<Self> {
+        match enc_name {
+            "gzip" => Ok(Compressor::Gzip(libflate::gzip::Encoder::new(vec![])?)),
+            "deflate" => Ok(Compressor::Deflate(libflate::zlib::Encoder::new(vec![])?)),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("unknown encoding {}", enc_name),
+            )),
+        }
+    }
+
+    fn write(&mut self, content: &[u8]) -> std::io::Result<()> {
+        use std::io::Write;
+        match self {
+            Compressor::Gzip(encoder) => encoder.write_all(content),
+            Compressor::Deflate(encoder) => encoder.write_all(content),
+        }
+    }
+
+    fn finish(self) -> std::io::Result<Vec<u8>> {
+        match self {
+            Compressor::Gzip(encoder) => encoder.finish().into_result(),
+            Compressor::Deflate(encoder) => encoder.finish().into_result(),
+        }
+    }
+}
+
+/// An asset being compressed by the heartbeat.
+struct Compression {
+    key: Key,
+    /// The hash of the identity content, which is compressed only if it
+    /// doesn't change meanwhile.
+    sha256: Hash,
+    /// How much of the identity content was compressed.
+    offset: usize,
+    compressors: Vec<(&'static str, Compressor)>,
+    /// The steps called since the last one which succeeded.
+    attempts: u32,
+}
+
+#[test]
+fn check_compress() {
+    use std::io::Read;
+    assert!(is_compressible("text/plain; charset=utf-8"));
+    assert!(is_compressible("Application/JSON"));
+    assert!(is_compressible("image/svg+xml"));
+    assert!(!is_compressible("image/png"));
+    assert!(!is_compressible("application/pdf"));
+    let content = b"a notarized document, a notarized document".repeat(100);
+    let compress = |enc_name| {
+        let mut compressor = Compressor::new(enc_name).unwrap();
+        for slice in content.chunks(1000) {
+            compressor.write(slice).unwrap();
+        }
+        compressor.finish().unwrap()
+    };
+    let gzip = compress("gzip");
+    assert!(gzip.len() < content.len());
+    let mut decoded = vec![];
+    libflate::gzip::Decoder::new(&gzip[..])
+        .unwrap()
+        .read_to_end(&mut decoded)
+        .unwrap();
+    assert_eq!(decoded, content);
+    let deflate = compress("deflate");
+    let mut decoded = vec![];
+    libflate::zlib::Decoder::new(&deflate[..])
+        .unwrap()
+        .read_to_end(&mut decoded)
+        .unwrap();
+    assert_eq!(decoded, content);
+    assert!(Compressor::new("br").is_err());
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct AssetEncodingV0 {
+    modified: Timestamp,
+    content_chunks: Vec<RcBytes>,
+    total_length: usize,
+    certified: bool,
+    sha256: [u8; 32],
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct AssetV0 {
+    content_type: String,
+    encodings: HashMap<String, AssetEncodingV0>,
+}
+
+/// Moves the assets to stable memory.
+fn migrate_assets(assets: HashMap<Key, AssetV0>) {
+    for (key, asset) in assets.into_iter() {
+        let encodings = asset
+            .encodings
+            .into_iter()
+            .map(|(name, enc)| {
+                let content = enc
+                    .content_chunks
+                    .iter()
+                    .flat_map(|c| c.iter().copied())
+                    .collect::<Vec<u8>>();
+                let enc = AssetEncoding {
+                    modified: enc.modified,
+                    content_chunks: crate::chunks::store(&content),
+                    total_length: enc.total_length,
+                    certified: enc.certified,
+                    sha256: enc.sha256,
+                };
+                (name, enc)
+            })
+            .collect();
+        let asset = Asset {
+            content_type: asset.content_type,
+            encodings,
+            status_code: None,
+        };
+        ASSETS.insert(&key, &asset);
+    }
+}
+
+#[cfg(test)]
+impl StableState {
+    pub fn role(&self, principal: &Principal) -> Option<Role> {
+        self.authorized
+            .iter()
+            .find(|a| a.principal == *principal)
+            .map(|a| a.role)
+    }
+}
+
+/// The content of an encoding of an asset, read from the blob region.
+#[cfg(test)]
+pub fn content(key: &str, enc_name: &str) -> Option<Vec<u8>> {
+    let asset = ASSETS.get(&key.to_string())?;
+    let enc = asset.encodings.get(enc_name)?;
+    Some(
+        enc.content_chunks
+            .iter()
+            .flat_map(|blob| crate::stable::get(*blob))
+            .collect(),
+    )
+}
+
+impl StableStateV0 {
+    /// Moves the assets to stable memory, returning what remains to save.
+    pub fn migrate(self) -> StableState {
+        let authorized = self
+            .authorized
+            .into_iter()
+            .map(|principal| AuthorizedPrincipal {
+                principal,
+                role: Role::Admin,
+            })
+            .collect();
+        migrate_assets(self.stable_assets);
+        StableState { authorized }
+    }
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
@@ -68,13 +385,15 @@ struct AssetDetails {
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
 struct AssetEncodingDetails {
-    modified: Timestamp,
+    modified: Int,
     content_encoding: String,
     sha256: Option<ByteBuf>,
     length: Nat,
 }
 type Timestamp = u64;
 pub type Key = String;
+type BatchId = Nat;
+type ChunkId = Nat;
 
 // HTTP interface
 
@@ -103,6 +422,9 @@ pub struct Token {
     index: Nat,
     // We don't care about the sha, we just want to be backward compatible.
     sha256: Option<ByteBuf>,
+    /// The capability link token of the request, see `crate::links`, which
+    /// is needed for every chunk of a hidden datum.
+    pub capability: Option<String>,
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
@@ -110,54 +432,669 @@ enum StreamingStrategy {
     Callback { callback: Func, token: Token },
 }
 
-#[derive(Clone, Debug, CandidType, Deserialize)]
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
 pub struct StreamingCallbackHttpResponse {
     body: RcBytes,
     token: Option<Token>,
 }
 
+impl HttpResponse {
+    /// Passes the capability on to the streaming callback.
+    pub fn set_capability(&mut self, capability: Option<String>) {
+        if let Some(StreamingStrategy::Callback { token, .. }) = self.streaming_strategy.as_mut() {
+            token.capability = capability;
+        }
+    }
+}
+
+impl StreamingCallbackHttpResponse {
+    pub fn set_capability(&mut self, capability: Option<String>) {
+        if let Some(token) = self.token.as_mut() {
+            token.capability = capability;
+        }
+    }
+}
+
+/// Authorizes `other` with `role`, by default `Uploader`, replacing any role
+/// it had.
 #[update]
-fn authorize(other: Principal) {
-    let caller = caller();
+fn authorize(other: Principal, role: Option<Role>) -> NotaryResult<()> {
+    is_authorized(&[Role::Admin])?;
+    let role = role.unwrap_or(Role::Uploader);
     STATE.with(|s| {
-        let caller_autorized = s.authorized.borrow().iter().any(|p| *p == caller);
-        if caller_autorized {
-            s.authorized.borrow_mut().push(other);
+        let mut authorized = s.authorized.borrow_mut();
+        if authorized.get(&other) == Some(&Role::Admin) && role != Role::Admin {
+            check_not_last_admin(&authorized)?;
         }
+        authorized.insert(other, role);
+        Ok(())
     })
 }
 
-/*
-pub fn do_get(key: Key) -> RcBytes {
+/// Removes any role of `other`.  The last admin can't be removed, which would
+/// leave the notary unmanageable.
+#[update]
+fn deauthorize(other: Principal) -> NotaryResult<()> {
+    is_authorized(&[Role::Admin])?;
     STATE.with(|s| {
-        let assets = s.assets.borrow();
-        let asset = assets.get(&key).unwrap_or_else(|| trap("asset not found"));
-        let id_enc = asset
-            .encodings
-            .get("identity")
-            .unwrap_or_else(|| trap("no identity encoding"));
-        if id_enc.content_chunks.len() > 1 {
-            trap("Asset too large. Use get() and get_chunk() instead.");
+        let mut authorized = s.authorized.borrow_mut();
+        if authorized.get(&other) == Some(&Role::Admin) {
+            check_not_last_admin(&authorized)?;
+        }
+        authorized.remove(&other);
+        Ok(())
+    })
+}
+
+fn check_not_last_admin(authorized: &HashMap<Principal, Role>) -> NotaryResult<()> {
+    match authorized.values().filter(|r| **r == Role::Admin).count() {
+        1 => Err(NotaryError::Unauthorized),
+        _ => Ok(()),
+    }
+}
+
+#[test]
+fn check_not_last_admin_guard() {
+    let mut authorized = HashMap::new();
+    authorized.insert(Principal::from_slice(&[1]), Role::Admin);
+    authorized.insert(Principal::from_slice(&[2]), Role::Uploader);
+    assert_eq!(
+        check_not_last_admin(&authorized),
+        Err(NotaryError::Unauthorized)
+    );
+    authorized.insert(Principal::from_slice(&[3]), Role::Admin);
+    assert_eq!(check_not_last_admin(&authorized), Ok(()));
+}
+
+#[query]
+fn list_authorized() -> NotaryResult<Vec<AuthorizedPrincipal>> {
+    is_authorized(&[Role::Admin, Role::Auditor])?;
+    Ok(STATE.with(|s| {
+        let mut list = s
+            .authorized
+            .borrow()
+            .iter()
+            .map(|(principal, role)| AuthorizedPrincipal {
+                principal: *principal,
+                role: *role,
+            })
+            .collect::<Vec<_>>();
+        list.sort_by_key(|a| (a.role, a.principal.to_text()));
+        list
+    }))
+}
+
+// Asset canister interface, as used by `dfx` and `icx-asset` to deploy the
+// frontend.  It returns plain records, so errors trap, which also rolls back
+// everything the call did.
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct CreateAssetArguments {
+    key: Key,
+    content_type: String,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct SetAssetContentArguments {
+    key: Key,
+    content_encoding: String,
+    chunk_ids: Vec<ChunkId>,
+    sha256: Option<ByteBuf>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct UnsetAssetContentArguments {
+    key: Key,
+    content_encoding: String,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct DeleteAssetArguments {
+    key: Key,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct ClearArguments {}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+enum BatchOperation {
+    CreateAsset(CreateAssetArguments),
+    SetAssetContent(SetAssetContentArguments),
+    UnsetAssetContent(UnsetAssetContentArguments),
+    DeleteAsset(DeleteAssetArguments),
+    Clear(ClearArguments),
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct CommitBatchArguments {
+    batch_id: BatchId,
+    operations: Vec<BatchOperation>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct CreateBatchArguments {}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct CreateBatchResponse {
+    batch_id: BatchId,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct CreateChunkArguments {
+    batch_id: BatchId,
+    content: ByteBuf,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct CreateChunkResponse {
+    chunk_id: ChunkId,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct StoreArguments {
+    key: Key,
+    content_type: String,
+    content_encoding: String,
+    content: ByteBuf,
+    sha256: Option<ByteBuf>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct GetArguments {
+    key: Key,
+    accept_encodings: Vec<String>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct GetChunkArguments {
+    key: Key,
+    content_encoding: String,
+    index: Nat,
+    sha256: Option<ByteBuf>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct GetChunkResponse {
+    content: RcBytes,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct ListArguments {}
+
+/// Whether `key` is that of a datum, "/" and the hex hash of its record.
+/// Only the notary changes those, the asset interface neither lists nor
+/// changes them.
+pub fn is_record_key(key: &str) -> bool {
+    key.strip_prefix('/')
+        .is_some_and(|name| name.len() >= 32 && name.bytes().all(|b| b.is_ascii_hexdigit()))
+}
+
+#[test]
+fn check_is_record_key() {
+    assert!(is_record_key(&format!("/{}", "ab".repeat(32))));
+    assert!(is_record_key(&format!("/{}", "AB".repeat(64))));
+    assert!(!is_record_key(&"ab".repeat(32)));
+    assert!(!is_record_key("/index.html"));
+    assert!(!is_record_key("/cafe"));
+    assert!(!is_record_key(&format!("/{}.js", "ab".repeat(32))));
+}
+
+fn check_uploader() {
+    if is_authorized(&[Role::Admin, Role::Uploader]).is_err() {
+        trap("caller is not authorized to upload assets");
+    }
+}
+
+fn check_asset_key(key: &str) {
+    if is_record_key(key) {
+        trap(&format!("{} is the key of a record", key));
+    }
+}
+
+/// Traps if the caller may not download the datum of a record key.
+fn check_download(key: &str) {
+    if is_record_key(key) && !crate::can_download(&key[1..], None) {
+        trap("hidden");
+    }
+}
+
+fn to_id(id: &Nat) -> u64 {
+    id.0.to_u64().unwrap_or(u64::MAX)
+}
+
+/// Drops the batches that weren't committed in time, with their chunks.
+fn expire_batches(s: &State, now: Timestamp) {
+    let mut batches = s.batches.borrow_mut();
+    batches.retain(|_, batch| batch.expires > now);
+    s.upload_chunks
+        .borrow_mut()
+        .retain(|_, chunk| batches.contains_key(&chunk.batch_id));
+}
+
+#[update]
+fn create_batch(_: CreateBatchArguments) -> CreateBatchResponse {
+    check_uploader();
+    let now = time() as u64;
+    STATE.with(|s| {
+        expire_batches(s, now);
+        let batch_id = s.next_batch_id.replace_with(|id| *id + 1);
+        s.batches.borrow_mut().insert(
+            batch_id,
+            Batch {
+                expires: now + BATCH_EXPIRY_NANOS,
+            },
+        );
+        CreateBatchResponse {
+            batch_id: Nat::from(batch_id),
         }
-        id_enc.content_chunks[0].clone()
     })
 }
-*/
-
-pub fn do_put(key: Key, hash: Hash, content_type: String, content: ByteBuf) {
-    let content_encoding = "identity".to_string();
-    STATE.with(move |s| {
-        let mut assets = s.assets.borrow_mut();
-        let asset = assets.entry(key.clone()).or_default();
-        asset.content_type = content_type;
-        let encoding = asset.encodings.entry(content_encoding).or_default();
-        encoding.total_length = content.len();
-        encoding.content_chunks = vec![RcBytes::from(content)];
-        encoding.modified = time() as u64;
-        encoding.sha256 = hash;
-
-        on_asset_change(&key, asset);
+
+#[update]
+fn create_chunk(arg: CreateChunkArguments) -> CreateChunkResponse {
+    check_uploader();
+    let now = time() as u64;
+    STATE.with(|s| {
+        expire_batches(s, now);
+        let batch_id = to_id(&arg.batch_id);
+        match s.batches.borrow_mut().get_mut(&batch_id) {
+            Some(batch) => batch.expires = now + BATCH_EXPIRY_NANOS,
+            None => trap("batch not found"),
+        }
+        let chunk_id = s.next_chunk_id.replace_with(|id| *id + 1);
+        s.upload_chunks.borrow_mut().insert(
+            chunk_id,
+            UploadChunk {
+                batch_id,
+                content: arg.content,
+            },
+        );
+        CreateChunkResponse {
+            chunk_id: Nat::from(chunk_id),
+        }
+    })
+}
+
+/// Applies all the operations, or none as any error traps.
+#[update]
+fn commit_batch(arg: CommitBatchArguments) {
+    check_uploader();
+    let batch_id = to_id(&arg.batch_id);
+    STATE.with(|s| {
+        if s.batches.borrow_mut().remove(&batch_id).is_none() {
+            trap("batch not found");
+        }
     });
+    for operation in arg.operations.into_iter() {
+        match operation {
+            BatchOperation::CreateAsset(arg) => do_create_asset(arg),
+            BatchOperation::SetAssetContent(arg) => do_set_asset_content(arg),
+            BatchOperation::UnsetAssetContent(arg) => do_unset_asset_content(arg),
+            BatchOperation::DeleteAsset(arg) => do_delete_asset(arg),
+            BatchOperation::Clear(_) => do_clear_assets(),
+        }
+    }
+    STATE.with(|s| {
+        s.upload_chunks
+            .borrow_mut()
+            .retain(|_, chunk| chunk.batch_id != batch_id)
+    });
+}
+
+#[update]
+fn create_asset(arg: CreateAssetArguments) {
+    check_uploader();
+    do_create_asset(arg);
+}
+
+#[update]
+fn set_asset_content(arg: SetAssetContentArguments) {
+    check_uploader();
+    do_set_asset_content(arg);
+}
+
+#[update]
+fn unset_asset_content(arg: UnsetAssetContentArguments) {
+    check_uploader();
+    do_unset_asset_content(arg);
+}
+
+#[update]
+fn delete_asset(arg: DeleteAssetArguments) {
+    check_uploader();
+    do_delete_asset(arg);
+}
+
+/// Creates or replaces an asset with content small enough for one message.
+#[update]
+fn store(arg: StoreArguments) {
+    check_uploader();
+    let StoreArguments {
+        key,
+        content_type,
+        content_encoding,
+        content,
+        sha256,
+    } = arg;
+    check_asset_key(&key);
+    let mut asset = ASSETS.get(&key).unwrap_or_default();
+    asset.content_type = content_type;
+    ASSETS.insert(&key, &asset);
+    set_encoding(key, content_encoding, &content, sha256);
+}
+
+fn do_create_asset(arg: CreateAssetArguments) {
+    check_asset_key(&arg.key);
+    match ASSETS.get(&arg.key) {
+        Some(asset) if asset.content_type != arg.content_type => {
+            trap(&format!("{} exists with another content type", arg.key))
+        }
+        Some(_) => {}
+        None => {
+            ASSETS.insert(
+                &arg.key,
+                &Asset {
+                    content_type: arg.content_type,
+                    ..Asset::default()
+                },
+            );
+        }
+    }
+}
+
+fn do_set_asset_content(arg: SetAssetContentArguments) {
+    check_asset_key(&arg.key);
+    let content = STATE.with(|s| {
+        let mut upload_chunks = s.upload_chunks.borrow_mut();
+        let mut content = vec![];
+        for chunk_id in arg.chunk_ids.iter() {
+            match upload_chunks.remove(&to_id(chunk_id)) {
+                Some(chunk) => content.extend_from_slice(&chunk.content),
+                None => trap("chunk not found"),
+            }
+        }
+        content
+    });
+    set_encoding(arg.key, arg.content_encoding, &content, arg.sha256);
+}
+
+/// Stores an encoding of an existing asset, replacing any it had.
+fn set_encoding(key: Key, content_encoding: String, content: &[u8], sha256: Option<ByteBuf>) {
+    let hash = hash_bytes(content);
+    if sha256.is_some_and(|sha256| sha256.as_slice() != hash) {
+        trap("sha256 mismatch");
+    }
+    let mut asset = ASSETS
+        .get(&key)
+        .unwrap_or_else(|| trap(&format!("{} not found", key)));
+    let enc = AssetEncoding {
+        modified: time() as u64,
+        content_chunks: crate::chunks::store(content),
+        total_length: content.len(),
+        certified: false,
+        sha256: hash,
+    };
+    if let Some(replaced) = asset.encodings.insert(content_encoding, enc) {
+        replaced.free();
+    }
+    recertify(&key, &mut asset);
+    ASSETS.insert(&key, &asset);
+}
+
+/// Certifies the asset afresh after its encodings changed.
+fn recertify(key: &str, asset: &mut Asset) {
+    for enc in asset.encodings.values_mut() {
+        enc.certified = false;
+    }
+    on_asset_change(key, asset);
+}
+
+fn do_unset_asset_content(arg: UnsetAssetContentArguments) {
+    check_asset_key(&arg.key);
+    let mut asset = ASSETS
+        .get(&arg.key)
+        .unwrap_or_else(|| trap(&format!("{} not found", arg.key)));
+    if let Some(enc) = asset.encodings.remove(&arg.content_encoding) {
+        enc.free();
+        recertify(&arg.key, &mut asset);
+        ASSETS.insert(&arg.key, &asset);
+    }
+}
+
+fn do_delete_asset(arg: DeleteAssetArguments) {
+    check_asset_key(&arg.key);
+    do_delete(&arg.key);
+}
+
+/// Deletes the assets other than the datums of records.
+fn do_clear_assets() {
+    let keys = ASSETS
+        .keys()
+        .filter(|key| !is_record_key(key))
+        .collect::<Vec<_>>();
+    for key in keys.iter() {
+        do_delete(key);
+    }
+}
+
+/// Returns the encoding of the asset first in `accept_encodings`, with the
+/// content of its first `STREAMING_CHUNK_SIZE` bytes.
+#[query]
+fn get(arg: GetArguments) -> EncodedAsset {
+    check_download(&arg.key);
+    let asset = ASSETS
+        .get(&arg.key)
+        .unwrap_or_else(|| trap(&format!("{} not found", arg.key)));
+    for enc_name in arg.accept_encodings.iter() {
+        if let Some(enc) = asset.encodings.get(enc_name) {
+            return EncodedAsset {
+                content: enc.read_range(0, STREAMING_CHUNK_SIZE),
+                content_type: asset.content_type.clone(),
+                content_encoding: enc_name.clone(),
+                total_length: Nat::from(enc.total_length),
+                sha256: Some(ByteBuf::from(enc.sha256)),
+            };
+        }
+    }
+    trap("no such encoding")
+}
+
+/// Returns the `STREAMING_CHUNK_SIZE` bytes at `index` of the encoding.
+#[query]
+fn get_chunk(arg: GetChunkArguments) -> GetChunkResponse {
+    check_download(&arg.key);
+    let enc = ASSETS
+        .get(&arg.key)
+        .and_then(|mut asset| asset.encodings.remove(&arg.content_encoding))
+        .unwrap_or_else(|| trap(&format!("{} not found", arg.key)));
+    if arg
+        .sha256
+        .is_some_and(|sha256| sha256.as_slice() != enc.sha256)
+    {
+        trap("sha256 mismatch");
+    }
+    let start = arg
+        .index
+        .0
+        .to_usize()
+        .and_then(|index| index.checked_mul(STREAMING_CHUNK_SIZE))
+        .filter(|start| *start < enc.total_length)
+        .unwrap_or_else(|| trap("chunk index out of range"));
+    GetChunkResponse {
+        content: enc.read_range(start, start + STREAMING_CHUNK_SIZE),
+    }
+}
+
+#[query]
+fn list(_: ListArguments) -> Vec<AssetDetails> {
+    // In the order of the keys, as the map is.
+    ASSETS
+        .keys()
+        .filter(|key| !is_record_key(key))
+        .filter_map(|key| {
+            let asset = ASSETS.get(&key)?;
+            Some(AssetDetails {
+                content_type: asset.content_type.clone(),
+                encodings: asset
+                    .encodings
+                    .iter()
+                    .map(|(enc_name, enc)| AssetEncodingDetails {
+                        modified: Int::from(enc.modified),
+                        content_encoding: enc_name.clone(),
+                        sha256: Some(ByteBuf::from(enc.sha256)),
+                        length: Nat::from(enc.total_length),
+                    })
+                    .collect(),
+                key,
+            })
+        })
+        .collect()
+}
+
+/// Returns the content of the identity encoding of the asset.
+pub fn do_get(key: &str) -> Option<ByteBuf> {
+    let asset = ASSETS.get(&key.to_string())?;
+    let enc = asset.encodings.get("identity")?;
+    let mut content = Vec::with_capacity(enc.total_length);
+    for blob in enc.content_chunks.iter() {
+        content.extend_from_slice(&crate::stable::get(*blob));
+    }
+    Some(ByteBuf::from(content))
+}
+
+/// Stores the content as the identity encoding, and queues compressible
+/// content for `compress_next`.
+pub fn do_put(key: Key, hash: Hash, content_type: String, content: &[u8]) {
+    let mut asset = ASSETS.get(&key).unwrap_or_default();
+    asset.content_type = content_type;
+    // The other encodings were of the replaced content.
+    asset.free();
+    asset.encodings.clear();
+    asset.encodings.insert(
+        "identity".to_string(),
+        AssetEncoding {
+            modified: time() as u64,
+            total_length: content.len(),
+            content_chunks: crate::chunks::store(content),
+            certified: false,
+            sha256: hash,
+        },
+    );
+    recertify(&key, &mut asset);
+    ASSETS.insert(&key, &asset);
+    if asset.needs_compression() {
+        STATE.with(|s| s.compress_queue.borrow_mut().push_back(key));
+    }
+}
+
+/// Whether the heartbeat should call `compress_next`, counting the attempt
+/// first so that a step which keeps trapping is given up.
+pub fn schedule_compression() -> bool {
+    STATE.with(|s| {
+        if *s.compress_in_flight.borrow() {
+            return false;
+        }
+        let mut compressing = s.compressing.borrow_mut();
+        if let Some(compression) = compressing.as_mut() {
+            compression.attempts += 1;
+            if compression.attempts > MAX_COMPRESSION_ATTEMPTS {
+                *compressing = None;
+            }
+        }
+        let scheduled = compressing.is_some() || !s.compress_queue.borrow().is_empty();
+        s.compress_in_flight.replace(scheduled);
+        scheduled
+    })
+}
+
+pub fn compression_done() {
+    STATE.with(|s| s.compress_in_flight.replace(false));
+}
+
+/// Does a step of the compression of the queued assets: takes the next
+/// asset off the queue, or compresses a slice of it, or stores its
+/// compressed encodings.  It is called by the heartbeat in a message of its
+/// own, which rolls back alone if it traps.
+#[update]
+fn compress_next() {
+    if caller() != ic_cdk::id() {
+        trap("only the canister compresses assets");
+    }
+    STATE.with(|s| {
+        let mut compressing = s.compressing.borrow_mut();
+        let compression = match compressing.as_mut() {
+            Some(compression) => compression,
+            None => {
+                let key = s.compress_queue.borrow_mut().pop_front();
+                *compressing = key.and_then(start_compression);
+                return;
+            }
+        };
+        let mut asset = match ASSETS.get(&compression.key) {
+            // A replaced asset was queued again by `do_put`.
+            Some(asset)
+                if asset.needs_compression()
+                    && asset.encodings["identity"].sha256 == compression.sha256 =>
+            {
+                asset
+            }
+            _ => {
+                *compressing = None;
+                return;
+            }
+        };
+        let identity = &asset.encodings["identity"];
+        if compression.offset < identity.total_length {
+            let end = (compression.offset + COMPRESSION_SLICE_SIZE).min(identity.total_length);
+            let slice = identity.read_range(compression.offset, end);
+            compression.offset = end;
+            compression.attempts = 0;
+            let failed = compression
+                .compressors
+                .iter_mut()
+                .any(|(_, compressor)| compressor.write(&slice).is_err());
+            if failed {
+                *compressing = None;
+            }
+            return;
+        }
+        let total_length = identity.total_length;
+        let compression = compressing.take().unwrap();
+        for (enc_name, compressor) in compression.compressors {
+            let compressed = match compressor.finish() {
+                Ok(compressed) if compressed.len() < total_length => compressed,
+                _ => continue,
+            };
+            asset.encodings.insert(
+                enc_name.to_string(),
+                AssetEncoding {
+                    modified: time() as u64,
+                    total_length: compressed.len(),
+                    content_chunks: crate::chunks::store(&compressed),
+                    certified: false,
+                    sha256: hash_bytes(&compressed),
+                },
+            );
+        }
+        recertify(&compression.key, &mut asset);
+        ASSETS.insert(&compression.key, &asset);
+    })
+}
+
+fn start_compression(key: Key) -> Option<Compression> {
+    let asset = ASSETS.get(&key).filter(|asset| asset.needs_compression())?;
+    let compressors = COMPRESSED_ENCODINGS
+        .iter()
+        .map(|enc_name| Compressor::new(enc_name).map(|compressor| (*enc_name, compressor)))
+        .collect::<std::io::Result<Vec<_>>>()
+        .ok()?;
+    Some(Compression {
+        sha256: asset.encodings["identity"].sha256,
+        key,
+        offset: 0,
+        compressors,
+        attempts: 0,
+    })
 }
 
 fn create_token(
@@ -165,16 +1102,17 @@ fn create_token(
     enc_name: &str,
     enc: &AssetEncoding,
     key: &str,
-    chunk_index: usize,
+    next_index: usize,
 ) -> Option<Token> {
-    if chunk_index + 1 >= enc.content_chunks.len() {
+    if next_index >= enc.content_chunks.len() {
         None
     } else {
         Some(Token {
             key: key.to_string(),
             content_encoding: enc_name.to_string(),
-            index: Nat::from(chunk_index + 1),
+            index: Nat::from(next_index),
             sha256: Some(ByteBuf::from(enc.sha256)),
+            capability: None,
         })
     }
 }
@@ -184,9 +1122,9 @@ fn create_strategy(
     enc_name: &str,
     enc: &AssetEncoding,
     key: &str,
-    chunk_index: usize,
+    next_index: usize,
 ) -> Option<StreamingStrategy> {
-    create_token(asset, enc_name, enc, key, chunk_index).map(|token| StreamingStrategy::Callback {
+    create_token(asset, enc_name, enc, key, next_index).map(|token| StreamingStrategy::Callback {
         callback: ic_cdk::export::candid::Func {
             method: "http_request_streaming_callback".to_string(),
             principal: ic_cdk::id(),
@@ -202,25 +1140,136 @@ fn build_200(
     key: &str,
     chunk_index: usize,
     certificate_header: Option<HeaderField>,
+    accept_ranges: bool,
 ) -> HttpResponse {
     let mut headers = vec![("Content-Type".to_string(), asset.content_type.to_string())];
     if enc_name != "identity" {
         headers.push(("Content-Encoding".to_string(), enc_name.to_string()));
     }
+    if accept_ranges && asset.status_code.is_none() {
+        headers.push(("Accept-Ranges".to_string(), "bytes".to_string()));
+    }
     if let Some(head) = certificate_header {
         headers.push(head);
     }
 
-    let streaming_strategy = create_strategy(asset, enc_name, enc, key, chunk_index);
+    let (body, next_index) = enc.read_chunks(chunk_index);
+    let streaming_strategy = create_strategy(asset, enc_name, enc, key, next_index);
 
     HttpResponse {
-        status_code: 200,
+        status_code: asset.status_code.unwrap_or(200),
         headers,
-        body: enc.content_chunks[chunk_index].clone(),
+        body,
         streaming_strategy,
     }
 }
 
+/// A byte range of a `Range` header, resolved against the content length.
+#[derive(Debug, PartialEq)]
+enum ByteRange {
+    /// The bytes from the first to before the second.
+    Satisfiable(usize, usize),
+    Unsatisfiable,
+}
+
+/// Parses the `Range` header of a request for `length` bytes.  Returns
+/// `None` for a header to ignore by serving the whole content: other units,
+/// several ranges or invalid syntax.
+fn parse_range(header: &str, length: usize) -> Option<ByteRange> {
+    let spec = header.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (first, last) = spec.split_once('-')?;
+    let (first, last) = (first.trim(), last.trim());
+    let (start, end) = if first.is_empty() {
+        let suffix: usize = last.parse().ok()?;
+        (length.saturating_sub(suffix), length)
+    } else {
+        let start: usize = first.parse().ok()?;
+        let end = match last {
+            "" => length,
+            last => {
+                let last: usize = last.parse().ok()?;
+                if last < start {
+                    return None;
+                }
+                last.saturating_add(1).min(length)
+            }
+        };
+        (start, end)
+    };
+    Some(if start < end {
+        ByteRange::Satisfiable(start, end)
+    } else {
+        ByteRange::Unsatisfiable
+    })
+}
+
+#[test]
+fn check_parse_range() {
+    use ByteRange::*;
+    assert_eq!(parse_range("bytes=0-99", 1000), Some(Satisfiable(0, 100)));
+    assert_eq!(
+        parse_range("bytes=500-", 1000),
+        Some(Satisfiable(500, 1000))
+    );
+    assert_eq!(
+        parse_range("bytes=-100", 1000),
+        Some(Satisfiable(900, 1000))
+    );
+    assert_eq!(parse_range("bytes=-2000", 1000), Some(Satisfiable(0, 1000)));
+    assert_eq!(
+        parse_range("bytes=900-2000", 1000),
+        Some(Satisfiable(900, 1000))
+    );
+    assert_eq!(parse_range("bytes=1000-", 1000), Some(Unsatisfiable));
+    assert_eq!(parse_range("bytes=-0", 1000), Some(Unsatisfiable));
+    assert_eq!(parse_range("bytes=0-", 0), Some(Unsatisfiable));
+    assert_eq!(parse_range("bytes=10-5", 1000), None);
+    assert_eq!(parse_range("bytes=0-1,5-6", 1000), None);
+    assert_eq!(parse_range("items=0-1", 1000), None);
+    assert_eq!(parse_range("bytes=a-b", 1000), None);
+}
+
+/// A partial response with the bytes from `start` to `end` of the identity
+/// encoding, at most `STREAMING_CHUNK_SIZE` of them as clients request the
+/// rest.  A range can't be checked against the certificate of the whole
+/// content, so it is uncertified and only served on the raw domain.
+fn build_206(asset: &Asset, enc: &AssetEncoding, start: usize, end: usize) -> HttpResponse {
+    let end = end.min(start + STREAMING_CHUNK_SIZE);
+    let content_range = format!("bytes {}-{}/{}", start, end - 1, enc.total_length);
+    HttpResponse {
+        status_code: 206,
+        headers: vec![
+            ("Content-Type".to_string(), asset.content_type.to_string()),
+            ("Accept-Ranges".to_string(), "bytes".to_string()),
+            ("Content-Range".to_string(), content_range),
+        ],
+        body: enc.read_range(start, end),
+        streaming_strategy: None,
+    }
+}
+
+fn build_416(length: usize) -> HttpResponse {
+    HttpResponse {
+        status_code: 416,
+        headers: vec![("Content-Range".to_string(), format!("bytes */{}", length))],
+        body: RcBytes::from(ByteBuf::from("range not satisfiable")),
+        streaming_strategy: None,
+    }
+}
+
+/// An uncertified error response, for requests we refuse to serve.
+pub fn build_error(status_code: u16, message: &str) -> HttpResponse {
+    HttpResponse {
+        status_code,
+        headers: vec![],
+        body: RcBytes::from(ByteBuf::from(message)),
+        streaming_strategy: None,
+    }
+}
+
 fn build_404(certificate_header: HeaderField) -> HttpResponse {
     HttpResponse {
         status_code: 404,
@@ -230,10 +1279,42 @@ fn build_404(certificate_header: HeaderField) -> HttpResponse {
     }
 }
 
-pub fn build_http_response(path: &str, encodings: Vec<String>, index: usize) -> HttpResponse {
-    STATE.with(|s| {
-        let assets = s.assets.borrow();
+/// Returns the first of `encodings` the asset has, and whether it can be
+/// served with the certificate of the asset.  That of the identity encoding
+/// also covers the compressed ones, as the gateway decodes the body before
+/// checking its hash.
+fn pick_encoding<'a>(
+    asset: &'a Asset,
+    encodings: &'a [String],
+) -> Option<(&'a str, &'a AssetEncoding, bool)> {
+    let identity_certified = asset
+        .encodings
+        .get("identity")
+        .is_some_and(|enc| enc.certified);
+    encodings.iter().find_map(|enc_name| {
+        let enc = asset.encodings.get(enc_name)?;
+        Some((&enc_name[..], enc, enc.certified || identity_certified))
+    })
+}
 
+/// Builds the response for the asset at `path` in the first of `encodings`
+/// it has.  On the `raw` domain, whose responses the gateway doesn't check,
+/// ranges are advertised and the bytes of the `range` header, if any, of its
+/// identity encoding are served.  The certified domain only serves whole
+/// contents, which can be checked against the certificate.
+pub fn build_http_response(
+    path: &str,
+    encodings: Vec<String>,
+    range: Option<&str>,
+    raw: bool,
+    index: usize,
+) -> HttpResponse {
+    // After an upgrade, an asset not yet certified again would be served
+    // without a certificate, or be proven absent and fall back to the index.
+    if is_rebuilding() && ASSET_HASHES.with(|t| t.borrow().get(path.as_bytes()).is_none()) {
+        return build_error(503, "rebuilding after an upgrade, retry later");
+    }
+    {
         let index_redirect_certificate = ASSET_HASHES.with(|t| {
             let tree = t.borrow();
             if tree.get(path.as_bytes()).is_none() && tree.get(INDEX_FILE.as_bytes()).is_some() {
@@ -247,18 +1328,17 @@ pub fn build_http_response(path: &str, encodings: Vec<String>, index: usize) ->
         });
 
         if let Some(certificate_header) = index_redirect_certificate {
-            if let Some(asset) = assets.get(INDEX_FILE) {
-                for (enc_name, enc) in asset.encodings.iter() {
-                    if enc.certified {
-                        return build_200(
-                            asset,
-                            enc_name,
-                            enc,
-                            INDEX_FILE,
-                            index,
-                            Some(certificate_header),
-                        );
-                    }
+            if let Some(asset) = ASSETS.get(&INDEX_FILE.to_string()) {
+                if let Some((enc_name, enc, true)) = pick_encoding(&asset, &encodings) {
+                    return build_200(
+                        &asset,
+                        enc_name,
+                        enc,
+                        INDEX_FILE,
+                        index,
+                        Some(certificate_header),
+                        false,
+                    );
                 }
             }
         }
@@ -266,39 +1346,28 @@ pub fn build_http_response(path: &str, encodings: Vec<String>, index: usize) ->
         let certificate_header =
             ASSET_HASHES.with(|t| witness_to_header(t.borrow().witness(path.as_bytes())));
 
-        if let Some(asset) = assets.get(path) {
-            for enc_name in encodings.iter() {
-                if let Some(enc) = asset.encodings.get(enc_name) {
-                    if enc.certified {
-                        return build_200(
-                            asset,
-                            enc_name,
-                            enc,
-                            path,
-                            index,
-                            Some(certificate_header),
-                        );
-                    } else {
-                        // Find if identity is certified, if it's not.
-                        if let Some(id_enc) = asset.encodings.get("identity") {
-                            if id_enc.certified {
-                                return build_200(
-                                    asset,
-                                    enc_name,
-                                    enc,
-                                    path,
-                                    index,
-                                    Some(certificate_header),
-                                );
-                            }
-                        }
+        if let Some(asset) = ASSETS.get(&path.to_string()) {
+            let identity = asset
+                .encodings
+                .get("identity")
+                .filter(|_| raw && asset.status_code.is_none());
+            if let (Some(range), Some(enc)) = (range, identity) {
+                match parse_range(range, enc.total_length) {
+                    Some(ByteRange::Satisfiable(start, end)) => {
+                        return build_206(&asset, enc, start, end)
                     }
+                    Some(ByteRange::Unsatisfiable) => return build_416(enc.total_length),
+                    None => {}
                 }
             }
+            if let Some((enc_name, enc, certified)) = pick_encoding(&asset, &encodings) {
+                let certificate_header = Some(certificate_header).filter(|_| certified);
+                return build_200(&asset, enc_name, enc, path, index, certificate_header, raw);
+            }
         }
 
         build_404(certificate_header)
-    })
+    }
 }
 
 /// An iterator-like structure that decode a URL.
@@ -327,9 +1396,8 @@ impl<'a> Iterator for UrlDecode<'a> {
     fn next(&mut self) -> Option<Self::Item> {
         let b = self.bytes.next()?;
         match b {
-            b'%' => Some(char::from(convert_percent(&mut self.bytes).expect(
-                "error decoding url: % must be followed by '%' or two hex digits",
-            ))),
+            // A % not followed by '%' or two hex digits is kept as is.
+            b'%' => Some(char::from(convert_percent(&mut self.bytes).unwrap_or(b'%'))),
             b'+' => Some(' '),
             x => Some(char::from(*x)),
         }
@@ -366,48 +1434,101 @@ pub fn http_request_streaming_callback(
         ..
     }: Token,
 ) -> StreamingCallbackHttpResponse {
-    STATE.with(|s| {
-        let assets = s.assets.borrow();
-        let asset = assets
-            .get(&key)
-            .expect("Invalid token on streaming: key not found.");
-        let enc = asset
-            .encodings
-            .get(&content_encoding)
-            .expect("Invalid token on streaming: encoding not found.");
-        // MAX is good enough. This means a chunk would be above 64-bits, which is impossible...
-        let chunk_index = index.0.to_usize().unwrap_or(usize::MAX);
-
-        StreamingCallbackHttpResponse {
-            body: enc.content_chunks[chunk_index].clone(),
-            token: create_token(&asset, &content_encoding, enc, &key, chunk_index),
-        }
-    })
+    // An invalid token gets an empty response which ends the stream.
+    let asset = match ASSETS.get(&key) {
+        Some(asset) => asset,
+        None => return StreamingCallbackHttpResponse::default(),
+    };
+    let enc = match asset.encodings.get(&content_encoding) {
+        Some(enc) => enc,
+        None => return StreamingCallbackHttpResponse::default(),
+    };
+    // MAX is good enough. This means a chunk would be above 64-bits, which is impossible...
+    let chunk_index = index.0.to_usize().unwrap_or(usize::MAX);
+    if chunk_index >= enc.content_chunks.len() {
+        return StreamingCallbackHttpResponse::default();
+    }
+    let (body, next_index) = enc.read_chunks(chunk_index);
+    StreamingCallbackHttpResponse {
+        body,
+        token: create_token(&asset, &content_encoding, enc, &key, next_index),
+    }
 }
 
-/*
 pub fn do_delete(key: &str) {
-    STATE.with(|s| {
-        let mut assets = s.assets.borrow_mut();
-        assets.remove(key);
-    });
+    if let Some(asset) = ASSETS.remove(&key.to_string()) {
+        asset.free();
+    }
     delete_asset_hash(key);
 }
-*/
 
-pub fn do_clear() {
-    STATE.with(|s| {
-        s.assets.borrow_mut().clear();
-    })
+/// Replaces the asset with a certified `410 Gone` tombstone giving the
+/// reason, so that requests for it don't fall back to the index file.
+pub fn do_takedown(key: Key, reason: &str) {
+    do_delete(&key);
+    let body = ByteBuf::from(format!("Gone: {}", reason));
+    let mut asset = Asset {
+        content_type: "text/plain; charset=utf-8".to_string(),
+        status_code: Some(410),
+        ..Asset::default()
+    };
+    asset.encodings.insert(
+        "identity".to_string(),
+        AssetEncoding {
+            modified: time() as u64,
+            total_length: body.len(),
+            certified: false,
+            sha256: hash_bytes(&body),
+            content_chunks: crate::chunks::store(&body),
+        },
+    );
+    on_asset_change(&key, &mut asset);
+    ASSETS.insert(&key, &asset);
+}
+
+/// Moves all the assets to the snapshot, dropping those of the snapshot of
+/// an earlier clear.
+pub fn take_snapshot() {
+    drop_snapshot();
+    ASSETS.swap(&SNAPSHOT_ASSETS);
+    ASSET_HASHES.with(|t| {
+        let mut tree = t.borrow_mut();
+        *tree = RbTree::new();
+        set_root_hash(&tree);
+    });
+}
+
+/// Restores the assets of the snapshot, replacing those with the same key.
+pub fn restore_snapshot() {
+    for (key, mut asset) in SNAPSHOT_ASSETS.iter() {
+        for enc in asset.encodings.values_mut() {
+            enc.certified = false;
+        }
+        on_asset_change(&key, &mut asset);
+        if let Some(replaced) = ASSETS.insert(&key, &asset) {
+            replaced.free();
+        }
+    }
+    SNAPSHOT_ASSETS.clear();
 }
 
-pub fn is_authorized() -> Result<(), String> {
+/// Drops the assets of the snapshot with their content.
+pub fn drop_snapshot() {
+    for (_, asset) in SNAPSHOT_ASSETS.iter() {
+        asset.free();
+    }
+    SNAPSHOT_ASSETS.clear();
+}
+
+/// Checks that the caller has one of `roles`.
+pub fn is_authorized(roles: &[Role]) -> NotaryResult<()> {
     STATE.with(|s| {
         s.authorized
             .borrow()
-            .contains(&caller())
-            .then(|| ())
-            .ok_or("Caller is not authorized".to_string())
+            .get(&caller())
+            .filter(|role| roles.contains(role))
+            .map(|_| ())
+            .ok_or(NotaryError::Unauthorized)
     })
 }
 
@@ -468,18 +1589,33 @@ fn delete_asset_hash(key: &str) {
 }
 
 fn set_root_hash(tree: &AssetHashes) {
-    use ic_certified_map::labeled_hash;
-    let full_tree_hash = labeled_hash(b"http_assets", &tree.root_hash());
+    use ic_certified_map::{fork_hash, labeled_hash};
+    let full_tree_hash = fork_hash(
+        &labeled_hash(b"http_assets", &tree.root_hash()),
+        &crate::receipts::labeled_root_hash(),
+    );
     set_certified_data(&full_tree_hash);
 }
 
+/// Recomputes the certified data after a change outside of the assets tree.
+pub fn update_root_hash() {
+    ASSET_HASHES.with(|t| set_root_hash(&t.borrow()));
+}
+
+/// The hash of the labeled assets tree, as included in the certified data.
+pub fn labeled_root_hash() -> Hash {
+    use ic_certified_map::labeled_hash;
+    ASSET_HASHES.with(|t| labeled_hash(b"http_assets", &t.borrow().root_hash()))
+}
+
 fn witness_to_header(witness: HashTree) -> HeaderField {
-    use ic_certified_map::labeled;
+    use ic_certified_map::{fork, labeled};
 
-    let hash_tree = labeled(b"http_assets", witness);
-    let mut serializer = serde_cbor::ser::Serializer::new(vec![]);
-    serializer.self_describe().unwrap();
-    hash_tree.serialize(&mut serializer).unwrap();
+    let hash_tree = fork(
+        labeled(b"http_assets", witness),
+        HashTree::Pruned(crate::receipts::labeled_root_hash()),
+    );
+    let tree = crate::receipts::serialize_tree(&hash_tree);
 
     let certificate = data_certificate().unwrap_or_else(|| trap("no data certificate available"));
 
@@ -488,7 +1624,7 @@ fn witness_to_header(witness: HashTree) -> HeaderField {
         String::from("certificate=:")
             + &base64::encode(&certificate)
             + ":, tree=:"
-            + &base64::encode(&serializer.into_inner())
+            + &base64::encode(&tree)
             + ":",
     )
 }
@@ -535,28 +1671,59 @@ pub fn hash_bytes(bytes: &[u8]) -> Hash {
 }
 
 pub fn init() {
-    do_clear();
-    STATE.with(|s| s.authorized.borrow_mut().push(caller()));
+    STATE.with(|s| s.authorized.borrow_mut().insert(caller(), Role::Admin));
+    update_root_hash();
 }
 
 pub fn pre_upgrade() -> StableState {
     STATE.with(|s| StableState {
-        authorized: s.authorized.take(),
-        stable_assets: s.assets.take(),
+        authorized: s
+            .authorized
+            .take()
+            .into_iter()
+            .map(|(principal, role)| AuthorizedPrincipal { principal, role })
+            .collect(),
     })
 }
 
+/// Restores the roles.  The certified data is that of the trees before the
+/// upgrade, it is set again for the empty ones which `rebuild_assets` and
+/// `crate::rebuild_records` fill.
 pub fn post_upgrade(stable_state: StableState) {
-    do_clear();
     STATE.with(|s| {
-        s.authorized.replace(stable_state.authorized);
-        s.assets.replace(stable_state.stable_assets);
+        s.authorized.replace(
+            stable_state
+                .authorized
+                .into_iter()
+                .map(|a| (a.principal, a.role))
+                .collect(),
+        );
+        s.rebuild.replace(Some(Key::new()));
+    });
+    update_root_hash();
+}
 
-        for (asset_name, asset) in s.assets.borrow_mut().iter_mut() {
-            for enc in asset.encodings.values_mut() {
-                enc.certified = false;
+fn is_rebuilding() -> bool {
+    STATE.with(|s| s.rebuild.borrow().is_some())
+}
+
+/// Certifies the next assets after an upgrade, the encodings certified
+/// before it, and queues those to compress.
+pub fn rebuild_assets() {
+    STATE.with(|s| {
+        let cursor = match s.rebuild.borrow().clone() {
+            Some(cursor) => cursor,
+            None => return,
+        };
+        let mut assets = ASSETS.range(&cursor);
+        for (key, asset) in assets.by_ref().take(crate::MAX_REBUILDS_PER_HEARTBEAT) {
+            for enc in asset.encodings.values().filter(|enc| enc.certified) {
+                certify_asset(key.clone(), &enc.sha256);
+            }
+            if asset.needs_compression() {
+                s.compress_queue.borrow_mut().push_back(key);
             }
-            on_asset_change(asset_name, asset);
         }
-    });
+        s.rebuild.replace(assets.next().map(|(key, _)| key));
+    })
 }
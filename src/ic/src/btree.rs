@@ -0,0 +1,766 @@
+//! Ordered maps in the blob region, see `crate::stable`, which survive
+//! upgrades as they are instead of being saved and restored.  Each map is a
+//! B-tree whose nodes are blocks of `NODE_SIZE` bytes, overwritten in place
+//! as they change, and whose root and length are in the header of the
+//! region.
+//!
+//! The entries of a node are ordered by the bytes of their keys.  A node is
+//! encoded as:
+//!
+//! | field    | encoding                                                  |
+//! |----------|-----------------------------------------------------------|
+//! | leaf     | u8, 1 for a leaf                                          |
+//! | count    | u8, the number of entries                                 |
+//! | entries  | the key, its length as a u16 and its bytes, then the      |
+//! |          | value, 0, its length as a u8 and its bytes, or 1 and the  |
+//! |          | offset, u64, and length, u32, of the blob holding it      |
+//! | children | for an inner node, `count + 1` offsets of nodes, u64      |
+//!
+//! Values longer than `MAX_INLINE_VALUE` bytes are blobs of their own.  All
+//! integers are little endian.
+
+use crate::stable::Blob;
+use dfnhack7_common::{AdminEvent, HashAlgorithm, Record};
+use std::cmp::Ordering;
+use std::convert::TryInto;
+use std::marker::PhantomData;
+
+/// The minimum degree: the nodes but the root have from `B - 1` to
+/// `2 * B - 1` entries.
+const B: usize = 6;
+const MIN_KEYS: usize = B - 1;
+const MAX_KEYS: usize = 2 * B - 1;
+/// Fits `MAX_KEYS` entries with the longest keys and inline values, and the
+/// children, in a node.
+const NODE_SIZE: usize = 4096;
+pub const MAX_KEY_SIZE: usize = 256;
+const MAX_INLINE_VALUE: usize = 32;
+
+/// The maps, whose headers are found by their position, so new ones go
+/// last.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MapId {
+    Records,
+    SnapshotRecords,
+    Digests,
+    Events,
+    AdminLog,
+    Reveals,
+    Chunks,
+    ChunkOffsets,
+    Assets,
+    SnapshotAssets,
+    #[cfg(test)]
+    Test,
+}
+
+/// The encoding of keys and values.  Keys are ordered by the bytes of their
+/// encoding, so integers are big endian there.
+pub trait Storable: Sized {
+    fn to_bytes(&self) -> Vec<u8>;
+    fn from_bytes(bytes: &[u8]) -> Self;
+}
+
+impl Storable for () {
+    fn to_bytes(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn from_bytes(_: &[u8]) -> Self {}
+}
+
+impl Storable for u64 {
+    fn to_bytes(&self) -> Vec<u8> {
+        self.to_be_bytes().to_vec()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        u64::from_be_bytes(bytes.try_into().expect("a stored u64"))
+    }
+}
+
+impl Storable for String {
+    fn to_bytes(&self) -> Vec<u8> {
+        self.as_bytes().to_vec()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        String::from_utf8(bytes.to_vec()).expect("a stored string")
+    }
+}
+
+impl Storable for [u8; 32] {
+    fn to_bytes(&self) -> Vec<u8> {
+        self.to_vec()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        bytes.try_into().expect("32 stored bytes")
+    }
+}
+
+/// Ordered by the number, then by the string.
+impl Storable for (u64, String) {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.0.to_bytes();
+        bytes.extend_from_slice(self.1.as_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        let (number, string) = bytes.split_at(8);
+        (u64::from_bytes(number), String::from_bytes(string))
+    }
+}
+
+/// Ordered by the algorithm, then by the string.
+impl Storable for (HashAlgorithm, String) {
+    fn to_bytes(&self) -> Vec<u8> {
+        let tag = match self.0 {
+            HashAlgorithm::Sha256 => 0,
+            HashAlgorithm::Sha512 => 1,
+            HashAlgorithm::Sha3_256 => 2,
+            HashAlgorithm::Blake3 => 3,
+        };
+        let mut bytes = vec![tag];
+        bytes.extend_from_slice(self.1.as_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        let algorithm = match bytes[0] {
+            0 => HashAlgorithm::Sha256,
+            1 => HashAlgorithm::Sha512,
+            2 => HashAlgorithm::Sha3_256,
+            3 => HashAlgorithm::Blake3,
+            tag => ic_cdk::trap(&format!("unknown stored algorithm {}", tag)),
+        };
+        (algorithm, String::from_bytes(&bytes[1..]))
+    }
+}
+
+/// Values stored as Candid, so that optional fields can be added to them
+/// without migrating the maps.
+macro_rules! candid_storable {
+    ($($t:ty),*) => {$(
+        impl Storable for $t {
+            fn to_bytes(&self) -> Vec<u8> {
+                candid::encode_one(self).expect("failed to encode a stored value")
+            }
+
+            fn from_bytes(bytes: &[u8]) -> Self {
+                candid::decode_one(bytes)
+                    .unwrap_or_else(|e| ic_cdk::trap(&format!("invalid stored value: {}", e)))
+            }
+        }
+    )*};
+}
+
+candid_storable!(
+    Record,
+    AdminEvent,
+    crate::LoggedEvent,
+    crate::assets::Asset,
+    crate::chunks::StoredChunk
+);
+
+enum Value {
+    Inline(Vec<u8>),
+    Blob(Blob),
+}
+
+impl Value {
+    fn new(bytes: Vec<u8>) -> Value {
+        if bytes.len() <= MAX_INLINE_VALUE {
+            Value::Inline(bytes)
+        } else {
+            Value::Blob(crate::stable::insert(&bytes))
+        }
+    }
+
+    fn bytes(&self) -> Vec<u8> {
+        match self {
+            Value::Inline(bytes) => bytes.clone(),
+            Value::Blob(blob) => crate::stable::get(*blob),
+        }
+    }
+
+    fn free(&self) {
+        if let Value::Blob(blob) = self {
+            crate::stable::remove(*blob);
+        }
+    }
+
+    /// Returns the bytes of a value removed from its map, freeing its blob.
+    fn take(self) -> Vec<u8> {
+        let bytes = self.bytes();
+        self.free();
+        bytes
+    }
+}
+
+struct Node {
+    offset: u64,
+    leaf: bool,
+    keys: Vec<Vec<u8>>,
+    values: Vec<Value>,
+    children: Vec<u64>,
+}
+
+fn node_blob(offset: u64) -> Blob {
+    Blob {
+        offset,
+        length: NODE_SIZE as u32,
+    }
+}
+
+/// Splits off the first `n` bytes of `bytes`.
+fn take<'a>(bytes: &mut &'a [u8], n: usize) -> &'a [u8] {
+    let (head, tail) = bytes.split_at(n);
+    *bytes = tail;
+    head
+}
+
+fn take_u64(bytes: &mut &[u8]) -> u64 {
+    u64::from_le_bytes(take(bytes, 8).try_into().unwrap())
+}
+
+impl Node {
+    /// Allocates an empty node.
+    fn allocate(leaf: bool) -> Node {
+        let mut node = Node {
+            offset: 0,
+            leaf,
+            keys: vec![],
+            values: vec![],
+            children: vec![],
+        };
+        node.offset = crate::stable::insert(&node.encode()).offset;
+        node
+    }
+
+    fn load(offset: u64) -> Node {
+        let block = crate::stable::get(node_blob(offset));
+        let mut bytes = &block[..];
+        let header = take(&mut bytes, 2);
+        let (leaf, count) = (header[0] == 1, header[1] as usize);
+        let mut keys = Vec::with_capacity(count);
+        let mut values = Vec::with_capacity(count);
+        for _ in 0..count {
+            let length = u16::from_le_bytes(take(&mut bytes, 2).try_into().unwrap());
+            keys.push(take(&mut bytes, length as usize).to_vec());
+            values.push(match take(&mut bytes, 1)[0] {
+                0 => {
+                    let length = take(&mut bytes, 1)[0];
+                    Value::Inline(take(&mut bytes, length as usize).to_vec())
+                }
+                _ => Value::Blob(Blob {
+                    offset: take_u64(&mut bytes),
+                    length: u32::from_le_bytes(take(&mut bytes, 4).try_into().unwrap()),
+                }),
+            });
+        }
+        let children = match leaf {
+            true => vec![],
+            false => (0..=count).map(|_| take_u64(&mut bytes)).collect(),
+        };
+        Node {
+            offset,
+            leaf,
+            keys,
+            values,
+            children,
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(NODE_SIZE);
+        bytes.push(self.leaf as u8);
+        bytes.push(self.keys.len() as u8);
+        for (key, value) in self.keys.iter().zip(self.values.iter()) {
+            bytes.extend_from_slice(&(key.len() as u16).to_le_bytes());
+            bytes.extend_from_slice(key);
+            match value {
+                Value::Inline(value) => {
+                    bytes.push(0);
+                    bytes.push(value.len() as u8);
+                    bytes.extend_from_slice(value);
+                }
+                Value::Blob(blob) => {
+                    bytes.push(1);
+                    bytes.extend_from_slice(&blob.offset.to_le_bytes());
+                    bytes.extend_from_slice(&blob.length.to_le_bytes());
+                }
+            }
+        }
+        for child in self.children.iter() {
+            bytes.extend_from_slice(&child.to_le_bytes());
+        }
+        bytes.resize(NODE_SIZE, 0);
+        bytes
+    }
+
+    fn save(&self) {
+        crate::stable::update(node_blob(self.offset), &self.encode());
+    }
+
+    fn free(&self) {
+        crate::stable::remove(node_blob(self.offset));
+    }
+
+    fn search(&self, key: &[u8]) -> Result<usize, usize> {
+        self.keys.binary_search_by(|k| k[..].cmp(key))
+    }
+}
+
+/// Splits the full child `i` of `parent` around its median entry, which
+/// moves up into `parent`, returning the new right half.
+fn split_child(parent: &mut Node, i: usize, child: &mut Node) -> Node {
+    let mut right = Node::allocate(child.leaf);
+    right.keys = child.keys.split_off(B);
+    right.values = child.values.split_off(B);
+    if !child.leaf {
+        right.children = child.children.split_off(B);
+    }
+    parent.keys.insert(i, child.keys.pop().unwrap());
+    parent.values.insert(i, child.values.pop().unwrap());
+    parent.children.insert(i + 1, right.offset);
+    right.save();
+    child.save();
+    parent.save();
+    right
+}
+
+/// Inserts into the subtree of `node`, which isn't full, splitting the full
+/// nodes on the way down.  Returns the value replaced, if any.
+fn insert_nonfull(mut node: Node, key: Vec<u8>, value: Value) -> Option<Value> {
+    loop {
+        let i = match node.search(&key) {
+            Ok(i) => {
+                let replaced = std::mem::replace(&mut node.values[i], value);
+                node.save();
+                return Some(replaced);
+            }
+            Err(i) => i,
+        };
+        if node.leaf {
+            node.keys.insert(i, key);
+            node.values.insert(i, value);
+            node.save();
+            return None;
+        }
+        let mut child = Node::load(node.children[i]);
+        if child.keys.len() == MAX_KEYS {
+            let right = split_child(&mut node, i, &mut child);
+            match key[..].cmp(&node.keys[i]) {
+                Ordering::Less => {}
+                // The median is the key, replace its value.
+                Ordering::Equal => continue,
+                Ordering::Greater => child = right,
+            }
+        }
+        node = child;
+    }
+}
+
+/// Merges child `i + 1` of `node` and the entry before it into child `i`.
+fn merge(node: &mut Node, i: usize, mut left: Node, right: Node) -> Node {
+    right.free();
+    left.keys.push(node.keys.remove(i));
+    left.values.push(node.values.remove(i));
+    left.keys.extend(right.keys);
+    left.values.extend(right.values);
+    left.children.extend(right.children);
+    node.children.remove(i + 1);
+    left.save();
+    node.save();
+    left
+}
+
+/// Returns child `i` of `node` with more than `MIN_KEYS` entries, moving one
+/// to it from a sibling through `node` or merging it with a sibling.
+fn fill_child(node: &mut Node, i: usize) -> Node {
+    let mut child = Node::load(node.children[i]);
+    if child.keys.len() > MIN_KEYS {
+        return child;
+    }
+    if i > 0 {
+        let mut left = Node::load(node.children[i - 1]);
+        if left.keys.len() > MIN_KEYS {
+            let key = std::mem::replace(&mut node.keys[i - 1], left.keys.pop().unwrap());
+            let value = std::mem::replace(&mut node.values[i - 1], left.values.pop().unwrap());
+            child.keys.insert(0, key);
+            child.values.insert(0, value);
+            if !left.leaf {
+                child.children.insert(0, left.children.pop().unwrap());
+            }
+            left.save();
+            child.save();
+            node.save();
+            return child;
+        }
+        if i + 1 == node.children.len() {
+            return merge(node, i - 1, left, child);
+        }
+    }
+    let mut right = Node::load(node.children[i + 1]);
+    if right.keys.len() > MIN_KEYS {
+        let key = std::mem::replace(&mut node.keys[i], right.keys.remove(0));
+        let value = std::mem::replace(&mut node.values[i], right.values.remove(0));
+        child.keys.push(key);
+        child.values.push(value);
+        if !right.leaf {
+            child.children.push(right.children.remove(0));
+        }
+        right.save();
+        child.save();
+        node.save();
+        return child;
+    }
+    merge(node, i, child, right)
+}
+
+/// Removes the last entry of the subtree of `node`, which has more than
+/// `MIN_KEYS` entries.
+fn remove_last(mut node: Node) -> (Vec<u8>, Value) {
+    while !node.leaf {
+        let i = node.children.len() - 1;
+        node = fill_child(&mut node, i);
+    }
+    let entry = (node.keys.pop().unwrap(), node.values.pop().unwrap());
+    node.save();
+    entry
+}
+
+/// Removes the first entry of the subtree of `node`, which has more than
+/// `MIN_KEYS` entries.
+fn remove_first(mut node: Node) -> (Vec<u8>, Value) {
+    while !node.leaf {
+        node = fill_child(&mut node, 0);
+    }
+    let entry = (node.keys.remove(0), node.values.remove(0));
+    node.save();
+    entry
+}
+
+/// Removes `key` from the subtree of `node`, which is the root or has more
+/// than `MIN_KEYS` entries, filling the nodes on the way down so that none
+/// has too few entries afterwards.
+fn remove_from(mut node: Node, key: &[u8]) -> Option<Value> {
+    loop {
+        match node.search(key) {
+            Ok(i) if node.leaf => {
+                node.keys.remove(i);
+                let value = node.values.remove(i);
+                node.save();
+                return Some(value);
+            }
+            // Replaced by its predecessor or successor, if a child can spare
+            // one, or else moved down with a merge.
+            Ok(i) => {
+                let left = Node::load(node.children[i]);
+                if left.keys.len() > MIN_KEYS {
+                    let (k, v) = remove_last(left);
+                    node.keys[i] = k;
+                    let value = std::mem::replace(&mut node.values[i], v);
+                    node.save();
+                    return Some(value);
+                }
+                let right = Node::load(node.children[i + 1]);
+                if right.keys.len() > MIN_KEYS {
+                    let (k, v) = remove_first(right);
+                    node.keys[i] = k;
+                    let value = std::mem::replace(&mut node.values[i], v);
+                    node.save();
+                    return Some(value);
+                }
+                node = merge(&mut node, i, left, right);
+            }
+            Err(_) if node.leaf => return None,
+            Err(i) => node = fill_child(&mut node, i),
+        }
+    }
+}
+
+/// A handle of a map in the blob region.  Entries are copied in and out, a
+/// value changed after `get` must be inserted again.
+pub struct StableMap<K, V> {
+    id: MapId,
+    _marker: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K, V> StableMap<K, V> {
+    pub const fn new(id: MapId) -> Self {
+        StableMap {
+            id,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<K: Storable, V: Storable> StableMap<K, V> {
+    fn header(&self) -> (u64, u64) {
+        crate::stable::map_header(self.id as usize)
+    }
+
+    fn set_header(&self, root: u64, length: u64) {
+        crate::stable::set_map_header(self.id as usize, root, length)
+    }
+
+    pub fn len(&self) -> u64 {
+        self.header().1
+    }
+
+    fn find(&self, key: &[u8]) -> Option<Value> {
+        let mut offset = self.header().0;
+        while offset != 0 {
+            let mut node = Node::load(offset);
+            match node.search(key) {
+                Ok(i) => return Some(node.values.swap_remove(i)),
+                Err(_) if node.leaf => return None,
+                Err(i) => offset = node.children[i],
+            }
+        }
+        None
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.find(&key.to_bytes())
+            .map(|value| V::from_bytes(&value.bytes()))
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.find(&key.to_bytes()).is_some()
+    }
+
+    /// Inserts an entry, returning the value it replaced.  Traps if the key
+    /// is longer than `MAX_KEY_SIZE`.
+    pub fn insert(&self, key: &K, value: &V) -> Option<V> {
+        let key = key.to_bytes();
+        if key.len() > MAX_KEY_SIZE {
+            ic_cdk::trap(&format!("key of {} bytes is too long", key.len()));
+        }
+        let value = Value::new(value.to_bytes());
+        let (root, length) = self.header();
+        let mut node = match root {
+            0 => Node::allocate(true),
+            root => Node::load(root),
+        };
+        if node.keys.len() == MAX_KEYS {
+            let mut parent = Node::allocate(false);
+            parent.children.push(node.offset);
+            split_child(&mut parent, 0, &mut node);
+            node = parent;
+        }
+        self.set_header(node.offset, length);
+        let replaced = insert_nonfull(node, key, value);
+        if replaced.is_none() {
+            self.set_header(self.header().0, length + 1);
+        }
+        replaced.map(|value| V::from_bytes(&value.take()))
+    }
+
+    pub fn remove(&self, key: &K) -> Option<V> {
+        let (root, length) = self.header();
+        if root == 0 {
+            return None;
+        }
+        let removed = remove_from(Node::load(root), &key.to_bytes());
+        // A merge on the way down, even to a missing key, may have emptied
+        // the root, its only child replaces it.
+        let node = Node::load(root);
+        let root = match (node.keys.is_empty(), node.leaf) {
+            (false, _) => root,
+            (true, leaf) => {
+                node.free();
+                if leaf {
+                    0
+                } else {
+                    node.children[0]
+                }
+            }
+        };
+        self.set_header(root, length - removed.is_some() as u64);
+        removed.map(|value| V::from_bytes(&value.take()))
+    }
+
+    /// Removes all the entries.
+    pub fn clear(&self) {
+        let mut offsets = vec![self.header().0];
+        while let Some(offset) = offsets.pop() {
+            if offset == 0 {
+                continue;
+            }
+            let node = Node::load(offset);
+            for value in node.values.iter() {
+                value.free();
+            }
+            offsets.extend_from_slice(&node.children);
+            node.free();
+        }
+        self.set_header(0, 0);
+    }
+
+    /// Exchanges the entries of the two maps.
+    pub fn swap(&self, other: &StableMap<K, V>) {
+        let (mine, theirs) = (self.header(), other.header());
+        self.set_header(theirs.0, theirs.1);
+        other.set_header(mine.0, mine.1);
+    }
+
+    /// Iterates over the entries in the order of their keys.  The map must
+    /// not change until the iterator is dropped.
+    pub fn iter(&self) -> Iter<K, V> {
+        let mut iter = Iter {
+            stack: vec![],
+            _marker: PhantomData,
+        };
+        iter.descend(self.header().0, None);
+        iter
+    }
+
+    /// Iterates over the keys, without reading the values, see `iter`.
+    pub fn keys(&self) -> Keys<K> {
+        let mut iter = Iter {
+            stack: vec![],
+            _marker: PhantomData,
+        };
+        iter.descend(self.header().0, None);
+        Keys(iter)
+    }
+
+    /// Iterates over the entries from `from` on, see `iter`.
+    pub fn range(&self, from: &K) -> Iter<K, V> {
+        let mut iter = Iter {
+            stack: vec![],
+            _marker: PhantomData,
+        };
+        iter.descend(self.header().0, Some(&from.to_bytes()));
+        iter
+    }
+}
+
+/// The nodes from the root to the next entry, each with the index of its
+/// next entry.
+pub struct Iter<K, V> {
+    stack: Vec<(Node, usize)>,
+    _marker: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K, V> Iter<K, V> {
+    /// Pushes the nodes down to the first entry of the subtree at `offset`
+    /// from `from` on.
+    fn descend(&mut self, mut offset: u64, from: Option<&[u8]>) {
+        while offset != 0 {
+            let node = Node::load(offset);
+            let (i, found) = match from.map(|from| node.search(from)) {
+                Some(Ok(i)) => (i, true),
+                Some(Err(i)) => (i, false),
+                None => (0, false),
+            };
+            offset = match node.leaf || found {
+                true => 0,
+                false => node.children[i],
+            };
+            self.stack.push((node, i));
+        }
+    }
+
+    /// Moves past the next entry, returning its key and, if `read`, the
+    /// bytes of its value.
+    fn next_entry(&mut self, read: bool) -> Option<(Vec<u8>, Vec<u8>)> {
+        loop {
+            let (node, i) = self.stack.last_mut()?;
+            if *i == node.keys.len() {
+                self.stack.pop();
+                continue;
+            }
+            let key = node.keys[*i].clone();
+            let value = match read {
+                true => node.values[*i].bytes(),
+                false => vec![],
+            };
+            *i += 1;
+            let child = match node.leaf {
+                true => 0,
+                false => node.children[*i],
+            };
+            self.descend(child, None);
+            return Some((key, value));
+        }
+    }
+}
+
+impl<K: Storable, V: Storable> Iterator for Iter<K, V> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        self.next_entry(true)
+            .map(|(key, value)| (K::from_bytes(&key), V::from_bytes(&value)))
+    }
+}
+
+pub struct Keys<K>(Iter<K, ()>);
+
+impl<K: Storable> Iterator for Keys<K> {
+    type Item = K;
+
+    fn next(&mut self) -> Option<K> {
+        self.0.next_entry(false).map(|(key, _)| K::from_bytes(&key))
+    }
+}
+
+#[test]
+fn check_stable_map() {
+    use std::collections::BTreeMap;
+    crate::stable::init();
+    let map: StableMap<u64, String> = StableMap::new(MapId::Test);
+    let mut expected = BTreeMap::new();
+    let mut state: u64 = 7;
+    for step in 0..20_000 {
+        state = state
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        let key = (state >> 33) % 2000;
+        // Both inline values and blobs.
+        let value = "v".repeat((state >> 20) as usize % 64) + &step.to_string();
+        match (state >> 40) % 3 {
+            0 => assert_eq!(map.remove(&key), expected.remove(&key)),
+            _ => assert_eq!(map.insert(&key, &value), expected.insert(key, value)),
+        }
+    }
+    assert_eq!(map.len(), expected.len() as u64);
+    assert!(map.iter().eq(expected.clone()));
+    assert!(map
+        .range(&1000)
+        .eq(expected.range(1000..).map(|(k, v)| (*k, v.clone()))));
+    assert!(map.range(&2000).next().is_none());
+    assert!(map.keys().eq(expected.keys().copied()));
+    for key in 0..2000 {
+        assert_eq!(map.get(&key), expected.get(&key).cloned());
+        assert_eq!(map.contains_key(&key), expected.contains_key(&key));
+    }
+
+    let other: StableMap<u64, String> = StableMap::new(MapId::Records);
+    other.insert(&1, &"other".to_string());
+    map.swap(&other);
+    assert_eq!(
+        map.iter().collect::<Vec<_>>(),
+        vec![(1, "other".to_string())]
+    );
+    assert!(other.iter().eq(expected.clone()));
+    other.swap(&map);
+
+    for key in expected.keys() {
+        assert!(map.remove(key).is_some());
+    }
+    assert_eq!(map.len(), 0);
+    assert_eq!(map.header(), (0, 0));
+    assert!(map.iter().next().is_none());
+    for key in 0..100 {
+        map.insert(&key, &"x".repeat(100));
+    }
+    map.clear();
+    assert_eq!(map.header(), (0, 0));
+    assert_eq!(map.get(&1), None);
+}
@@ -0,0 +1,178 @@
+//! A content addressed store of chunks in the blob region, see
+//! `crate::stable`.  Content is split where its bytes, not their offsets,
+//! say so (FastCDC with normalized chunking), so revisions of a file share
+//! all the chunks but those around the changes.  Chunks are reference
+//! counted and freed when the last content using them is.  The index of
+//! the chunks is a map in stable memory as well, see `crate::btree`.
+
+use crate::btree::{MapId, StableMap};
+use crate::stable::Blob;
+use dfnhack7_common::StorageStats;
+use ic_cdk::export::candid::{CandidType, Deserialize};
+use sha2::Digest;
+
+const MIN_CHUNK_SIZE: usize = 16 * 1024;
+const AVERAGE_CHUNK_SIZE: usize = 64 * 1024;
+pub const MAX_CHUNK_SIZE: usize = 256 * 1024;
+/// Cut points are harder to find before the average size and easier after,
+/// which narrows the distribution of chunk sizes.
+const MASK_SMALL: u64 = !0 << (64 - 18);
+const MASK_LARGE: u64 = !0 << (64 - 14);
+
+/// Random values for the gear rolling hash, from splitmix64.
+const GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0; 256];
+    let mut state: u64 = 0x6466_6e68_6163_6b37;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// The length of the first chunk of `data`.
+fn cut(data: &[u8]) -> usize {
+    if data.len() <= MIN_CHUNK_SIZE {
+        return data.len();
+    }
+    let end = data.len().min(MAX_CHUNK_SIZE);
+    let normal = end.min(AVERAGE_CHUNK_SIZE);
+    let mut hash: u64 = 0;
+    for (i, byte) in data.iter().enumerate().take(end).skip(MIN_CHUNK_SIZE) {
+        hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+        let mask = if i < normal { MASK_SMALL } else { MASK_LARGE };
+        if hash & mask == 0 {
+            return i + 1;
+        }
+    }
+    end
+}
+
+/// Splits `data` into content defined chunks, none for no data.
+pub fn split(mut data: &[u8]) -> Vec<&[u8]> {
+    let mut chunks = vec![];
+    while !data.is_empty() {
+        let (chunk, rest) = data.split_at(cut(data));
+        chunks.push(chunk);
+        data = rest;
+    }
+    chunks
+}
+
+type ChunkHash = [u8; 32];
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct StoredChunk {
+    hash: ChunkHash,
+    blob: Blob,
+    /// The number of encodings using the chunk, once per use.
+    refs: u32,
+}
+
+const CHUNKS: StableMap<ChunkHash, StoredChunk> = StableMap::new(MapId::Chunks);
+/// The hash of the chunk stored at each offset, to release a blob.
+const OFFSETS: StableMap<u64, ChunkHash> = StableMap::new(MapId::ChunkOffsets);
+
+fn insert(chunk: &[u8]) -> Blob {
+    let hash: ChunkHash = sha2::Sha256::digest(chunk).into();
+    if let Some(mut stored) = CHUNKS.get(&hash) {
+        stored.refs += 1;
+        CHUNKS.insert(&hash, &stored);
+        return stored.blob;
+    }
+    let blob = crate::stable::insert(chunk);
+    CHUNKS.insert(
+        &hash,
+        &StoredChunk {
+            hash,
+            blob,
+            refs: 1,
+        },
+    );
+    OFFSETS.insert(&blob.offset, &hash);
+    blob
+}
+
+/// Stores `content` as chunks, sharing those already stored.
+pub fn store(content: &[u8]) -> Vec<Blob> {
+    split(content).into_iter().map(insert).collect()
+}
+
+/// Releases a use of a chunk returned by `store`, freeing it after the last.
+pub fn release(blob: Blob) {
+    let hash = match OFFSETS.get(&blob.offset) {
+        Some(hash) => hash,
+        // A blob stored before the chunk store was never shared.
+        None => return crate::stable::remove(blob),
+    };
+    let mut stored = CHUNKS.get(&hash).expect("chunk of a stored offset");
+    stored.refs -= 1;
+    if stored.refs == 0 {
+        CHUNKS.remove(&hash);
+        OFFSETS.remove(&blob.offset);
+        crate::stable::remove(blob);
+    } else {
+        CHUNKS.insert(&hash, &stored);
+    }
+}
+
+pub fn stats() -> StorageStats {
+    let mut stats = StorageStats::default();
+    for (_, stored) in CHUNKS.iter() {
+        let length = stored.blob.length as u64;
+        stats.chunks += 1;
+        stats.stored_bytes += length;
+        stats.references += stored.refs as u64;
+        stats.referenced_bytes += length * stored.refs as u64;
+    }
+    stats
+}
+
+#[cfg(test)]
+fn random_bytes(length: usize, seed: u64) -> Vec<u8> {
+    let mut state = seed;
+    (0..length)
+        .map(|_| {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (state >> 33) as u8
+        })
+        .collect()
+}
+
+#[test]
+fn check_split() {
+    assert!(split(&[]).is_empty());
+    assert_eq!(split(&[1; 100]), vec![&[1u8; 100][..]]);
+    let data = random_bytes(4 * 1024 * 1024, 1);
+    let chunks = split(&data);
+    assert_eq!(chunks.concat(), data);
+    assert!(chunks[..chunks.len() - 1]
+        .iter()
+        .all(|c| c.len() >= MIN_CHUNK_SIZE && c.len() <= MAX_CHUNK_SIZE));
+    let average = data.len() / chunks.len();
+    assert!(average > AVERAGE_CHUNK_SIZE / 2 && average < AVERAGE_CHUNK_SIZE * 2);
+}
+
+#[test]
+fn check_split_resynchronizes() {
+    let data = random_bytes(2 * 1024 * 1024, 2);
+    let mut edited = data.clone();
+    edited.splice(1000..1000, b"an insertion".iter().cloned());
+    edited[1024 * 1024] ^= 0xff;
+    let original = split(&data);
+    let shared = split(&edited)
+        .into_iter()
+        .filter(|c| original.contains(c))
+        .count();
+    // Only the chunks around the two edits differ.
+    assert!(shared + 4 >= original.len());
+}
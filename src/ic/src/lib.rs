@@ -1,45 +1,371 @@
 mod assets;
+mod btree;
+mod chunks;
+mod links;
+mod query;
 mod rc_bytes;
+mod receipts;
+mod search;
+mod stable;
+mod upgrade;
 
+use crate::btree::{MapId, StableMap};
 use candid::{CandidType, Deserialize};
 use dfnhack7_common::*;
 use fuzzy_matcher::skim::SkimMatcherV2;
 use fuzzy_matcher::FuzzyMatcher;
 use ic_cdk::api::{caller, time};
+use ic_cdk::export::candid::Principal;
 use ic_cdk_macros::{init, post_upgrade, pre_upgrade, query, update};
+use serde_bytes::ByteBuf;
+use sha2::Digest;
 use std::cell::RefCell;
 use std::cmp::Ordering;
-use std::collections::hash_map::Entry;
-use std::collections::{HashMap, HashSet};
+use std::collections::HashMap;
 use std::fmt::Debug;
 
 thread_local! {
     static STATE: State = State::default();
 }
 
+const RECORDS: StableMap<Hash, Record> = StableMap::new(MapId::Records);
+/// The records removed by the last clear, see `Snapshot`.
+const SNAPSHOT_RECORDS: StableMap<Hash, Record> = StableMap::new(MapId::SnapshotRecords);
+/// Maps every digest of every record to the key of the record.
+const DIGESTS: StableMap<(HashAlgorithm, Hash), Hash> = StableMap::new(MapId::Digests);
+/// The change feed, by sequence number.
+const EVENTS: StableMap<u64, LoggedEvent> = StableMap::new(MapId::Events);
+const ADMIN_LOG: StableMap<u64, AdminEvent> = StableMap::new(MapId::AdminLog);
+/// The keys of the records with a scheduled reveal, by time.
+const REVEALS: StableMap<(Timestamp, Hash), ()> = StableMap::new(MapId::Reveals);
+
 const MAX_DESCRIPTION_LENGTH: usize = 200;
+/// The maximum size of a datum sent with the chunked upload protocol.
+const MAX_UPLOAD_SIZE: usize = 64 * 1024 * 1024;
+/// The maximum number of uncommitted uploads per principal.
+const MAX_OPEN_UPLOADS: usize = 4;
+/// The maximum number of bytes in the uncommitted uploads of a principal.
+const MAX_PRINCIPAL_UPLOAD_BYTES: usize = 2 * MAX_UPLOAD_SIZE;
+/// The maximum number of bytes in all uncommitted uploads together.
+const MAX_PENDING_UPLOAD_BYTES: usize = 512 * 1024 * 1024;
+/// The default number of results per page of `search` and `get_data`.
 const MAX_SEARCH_RESULTS: usize = 20;
+const MAX_SEARCH_PAGE: usize = 200;
+/// Divides the fuzzy match score of a search result before adding it to its
+/// BM25 score, so that a full fuzzy match of a short query is worth about
+/// as much as a rare token.
+const FUZZY_SCORE_SCALE: f64 = 100.0;
+const MAX_UPDATES_PAGE: usize = 100;
+/// The digests computed for every notarized datum, SHA-256 first.
+const DATUM_ALGORITHMS: &[HashAlgorithm] = HashAlgorithm::ALL;
+/// Uploads which no chunk was added to for this long are dropped.
+const UPLOAD_IDLE_EXPIRY_NANOS: u64 = 10 * 60 * 1_000_000_000;
+/// Bound the work of a single heartbeat.
+const MAX_REVEALS_PER_HEARTBEAT: usize = 100;
+const MAX_REBUILDS_PER_HEARTBEAT: usize = 500;
+/// The roles which see all records, hidden ones included.
+const READER_ROLES: &[Role] = &[Role::Admin, Role::Moderator, Role::Auditor];
+/// How long the admin requesting a clear must wait to confirm it alone.
+const CLEAR_DELAY_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+const CLEAR_REQUEST_EXPIRY_NANOS: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+/// How long a clear can be undone.
+const CLEAR_RETENTION_NANOS: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
 
+/// The state besides the maps in stable memory: what is saved on upgrade,
+/// and the certification tree and search index, which are rebuilt after.
 #[derive(Default)]
 struct State {
-    data: RefCell<HashMap<Hash, Record>>,
     matcher: RefCell<SkimMatcherV2>,
+    index: RefCell<crate::search::SearchIndex>,
+    uploads: RefCell<HashMap<UploadId, Upload>>,
+    next_upload_id: RefCell<UploadId>,
+    /// Signs capability links and salts description hashes, empty until
+    /// either is first needed.
+    secret: RefCell<Vec<u8>>,
+    pending_clear: RefCell<Option<PendingClear>>,
+    /// The last clear, until its retention window passes.
+    snapshot: RefCell<Option<Snapshot>>,
+    /// After an upgrade, the key of the next record to certify and index,
+    /// see `rebuild_records`.
+    rebuild: RefCell<Option<Hash>>,
 }
 
+/// A clear, whose records and assets are kept in the snapshot maps until
+/// `undo_clear` restores them or its retention window passes.
 #[derive(Clone, Debug, CandidType, Deserialize)]
-struct StableState {
-    data: HashMap<Hash, Record>,
-    assets: crate::assets::StableState,
+struct Snapshot {
+    taken: Timestamp,
+}
+
+/// An event of the change feed, with what decides who sees it once its
+/// record is gone.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct LoggedEvent {
+    event: RecordEvent,
+    owner: Principal,
+    /// Whether the record was hidden at the time of the event.
+    hidden: bool,
+}
+
+/// A datum being uploaded in chunks, see `begin_upload`.
+struct Upload {
+    owner: Principal,
+    content_type: String,
+    description: String,
+    hidden: bool,
+    reveal_at: Option<Timestamp>,
+    content: Vec<u8>,
+    /// Hash the content as it is uploaded, one of each `DATUM_ALGORITHMS`.
+    hashers: Vec<Hasher>,
+    last_active: Timestamp,
+}
+
+/// Returns the key of the record which has `hash` as its digest for
+/// `algorithm`.
+fn resolve_digest(algorithm: HashAlgorithm, hash: &str) -> Option<Hash> {
+    DIGESTS.get(&(algorithm, hash.to_lowercase()))
+}
+
+/// Returns the key of the record which has `hash` as any of its digests,
+/// trying the algorithms in the order of `HashAlgorithm::ALL`.
+fn resolve(hash: &str) -> Option<Hash> {
+    HashAlgorithm::ALL
+        .iter()
+        .find_map(|algorithm| resolve_digest(*algorithm, hash))
+}
+
+fn insert_record(s: &State, record: Record) -> RecordResult {
+    let result = to_result(&record, &Viewer::caller());
+    index_record(s, &record);
+    log_event(&record, RecordEventKind::Created);
+    RECORDS.insert(&record.hash, &record);
+    result
+}
+
+/// Certifies the record and adds it to the digest and search indexes.  Only
+/// public descriptions are searchable, hidden ones are added on `reveal`.
+fn index_record(s: &State, record: &Record) {
+    insert_digests(record);
+    certify_record(s, record);
+}
+
+fn insert_digests(record: &Record) {
+    for digest in record.digests.iter() {
+        DIGESTS.insert(&(digest.algorithm, digest.hash.clone()), &record.hash);
+    }
+}
+
+/// Certifies the record and adds it to the search index, which unlike the
+/// digest index aren't kept in stable memory.
+fn certify_record(s: &State, record: &Record) {
+    crate::receipts::certify_record(record);
+    s.index.borrow_mut().insert(
+        &record.hash,
+        record
+            .digests
+            .iter()
+            .map(|d| &d.hash[..])
+            .chain(record.public_attestations().map(|a| &a.description[..])),
+    );
+}
+
+fn log_event(record: &Record, kind: RecordEventKind) {
+    log_event_as(record, kind, caller());
+}
+
+/// Logs an event on behalf of `principal`, e.g. from the heartbeat which has
+/// no caller.
+fn log_event_as(record: &Record, kind: RecordEventKind, principal: Principal) {
+    let sequence = EVENTS.len();
+    EVENTS.insert(
+        &sequence,
+        &LoggedEvent {
+            event: RecordEvent {
+                sequence,
+                hash: record.hash.clone(),
+                kind,
+                principal,
+                timestamp: time() as u64,
+            },
+            owner: record.owner,
+            hidden: record.hidden,
+        },
+    );
+}
+
+/// Schedules, or with `None` cancels, the reveal of a hidden record.
+fn set_reveal_at(record: &mut Record, reveal_at: Option<Timestamp>) {
+    if let Some(t) = record.reveal_at.take() {
+        REVEALS.remove(&(t, record.hash.clone()));
+    }
+    if let Some(t) = reveal_at.filter(|_| record.hidden) {
+        REVEALS.insert(&(t, record.hash.clone()), &());
+        record.reveal_at = Some(t);
+    }
+}
+
+/// Makes the record public if `principal` is its owner, and the hidden
+/// attestations of `principal`.  Returns whether anything changed, the
+/// record must then be reindexed.
+fn unhide(record: &mut Record, principal: &Principal) -> bool {
+    let mut changed = false;
+    if *principal == record.owner && record.hidden {
+        set_reveal_at(record, None);
+        record.hidden = false;
+        record.revealed = Some(time() as u64);
+        changed = true;
+    }
+    for attestation in record.attestations.iter_mut() {
+        if attestation.owner == *principal && attestation.hidden {
+            attestation.hidden = false;
+            changed = true;
+        }
+    }
+    changed
+}
+
+/// Adds an attestation by the caller to an existing record.  Each principal
+/// can attest a hash only once.  The secret must have been drawn with
+/// `ensure_secret`.
+fn attest(s: &State, record: &mut Record, description: String, hidden: bool) -> NotaryResult<()> {
+    let owner = caller();
+    if record.attestations.iter().any(|a| a.owner == owner) {
+        return Err(NotaryError::AlreadyExists);
+    }
+    let created = time() as u64;
+    record.attestations.push(Attestation {
+        owner,
+        created,
+        salt: ByteBuf::from(description_salt(
+            &s.secret.borrow(),
+            &record.hash,
+            &owner,
+            created,
+        )),
+        description,
+        hidden,
+    });
+    Ok(())
+}
+
+/// The salt of the description hash of an attestation, which can't be
+/// guessed without the canister secret.
+fn description_salt(secret: &[u8], key: &str, owner: &Principal, created: Timestamp) -> Vec<u8> {
+    assert!(!secret.is_empty(), "the secret has not been drawn");
+    let mut salt = sha2::Sha256::new();
+    salt.update(b"dfnhack7-salt");
+    for field in [secret, key.as_bytes(), owner.as_slice()] {
+        salt.update((field.len() as u32).to_be_bytes());
+        salt.update(field);
+    }
+    salt.update(created.to_be_bytes());
+    salt.finalize().to_vec()
+}
+
+fn check_description(description: &str) -> NotaryResult<()> {
+    if description.len() > MAX_DESCRIPTION_LENGTH {
+        return Err(NotaryError::DescriptionTooLong);
+    }
+    Ok(())
 }
 
-fn to_result(r: &Record) -> RecordResult {
+/// The caller of a method, to decide which hidden records and attestations it
+/// may see.
+struct Viewer {
+    principal: Principal,
+    /// Principals with a `READER_ROLES` role see everything.
+    authorized: bool,
+    now: Timestamp,
+}
+
+impl Viewer {
+    fn caller() -> Viewer {
+        Viewer {
+            principal: caller(),
+            authorized: is_authorized(READER_ROLES).is_ok(),
+            now: time() as u64,
+        }
+    }
+
+    /// Whether the viewer may read the record and its datum while it is
+    /// hidden: its owner and those it is granted to.
+    fn has_access(&self, r: &Record) -> bool {
+        self.authorized
+            || self.principal == r.owner
+            || r.grants
+                .iter()
+                .any(|g| g.grantee == self.principal && g.is_active(self.now))
+    }
+
+    fn can_download(&self, r: &Record) -> bool {
+        !r.hidden || self.has_access(r)
+    }
+
+    /// A hidden record is also visible to those who attested it.
+    fn can_see(&self, r: &Record) -> bool {
+        self.can_download(r) || r.attestations.iter().any(|a| a.owner == self.principal)
+    }
+
+    /// Hidden attestations are visible to their owners and, for those of
+    /// the owner of the record, to whoever has access to the record.
+    fn can_see_attestation(&self, r: &Record, a: &Attestation) -> bool {
+        (!r.hidden && !a.hidden)
+            || self.principal == a.owner
+            || ((!a.hidden || a.owner == r.owner) && self.has_access(r))
+    }
+}
+
+/// Converts a record for `viewer`, redacting the descriptions hidden from it
+/// along with their salts.
+fn to_result(r: &Record, viewer: &Viewer) -> RecordResult {
+    let description = |a: &Attestation| {
+        if viewer.can_see_attestation(r, a) {
+            a.description.clone()
+        } else {
+            String::new()
+        }
+    };
+    let priority = r.priority();
     RecordResult {
         hash: r.hash.clone(),
+        algorithm: r.algorithm,
+        digests: r.digests.clone(),
         owner: r.owner.clone(),
         has_datum: r.datum.is_some(),
-        description: r.description.clone(),
+        description: description(priority),
         hidden: r.hidden,
-        created: r.created,
+        created: priority.created,
+        attestations: r
+            .attestations
+            .iter()
+            .enumerate()
+            .map(|(i, a)| AttestationResult {
+                owner: a.owner,
+                created: a.created,
+                description: description(a),
+                description_hash: hex::encode(description_hash(&a.salt, &a.description)),
+                salt: if viewer.can_see_attestation(r, a) {
+                    hex::encode(&a.salt)
+                } else {
+                    String::new()
+                },
+                hidden: a.hidden,
+                priority: i == 0,
+            })
+            .collect(),
+        grants: if viewer.authorized || viewer.principal == r.owner {
+            r.grants.clone()
+        } else {
+            vec![]
+        },
+        reveal_at: r.reveal_at,
+        revealed: r.revealed,
+        original_owner: r.previous_owners.first().map_or(r.owner, |p| p.owner),
+        previous_owners: r.previous_owners.clone(),
+        transfer_to: r.transfer_to.filter(|recipient| {
+            viewer.authorized || viewer.principal == r.owner || viewer.principal == *recipient
+        }),
+        takedown: r.takedown.clone(),
     }
 }
 
@@ -55,216 +381,1048 @@ fn http_request(req: crate::assets::HttpRequest) -> crate::assets::HttpResponse
     }
     encodings.push("identity".to_string());
 
-    let path = match req.url.find('?') {
-        Some(i) => &req.url[..i],
-        None => &req.url[..],
+    let (path, query) = match req.url.find('?') {
+        Some(i) => (&req.url[..i], &req.url[i + 1..]),
+        None => (&req.url[..], ""),
     };
-    let key = &path[1..];
-    STATE.with(move |s| match s.data.borrow_mut().entry(key.to_string()) {
-        Entry::Occupied(e) => {
-            // NOTE: the caller() is not the same as that for the asset
-            // canister because of limitations of II.  We can also
-            // fix this by merging the two canisters.
-            assert!(!e.get().hidden || caller() == e.get().owner);
-        }
-        Entry::Vacant(_e) => {}
-    });
+    let path = crate::assets::url_decode(path);
+    // A capability link token is given as the `token` parameter or header.
+    let capability = query
+        .split('&')
+        .find_map(|p| p.strip_prefix("token="))
+        .map(crate::assets::url_decode)
+        .or_else(|| {
+            req.headers
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case("X-Capability-Token"))
+                .map(|(_, value)| value.clone())
+        });
+    // NOTE: the HTTP gateway makes anonymous queries, also for a frontend
+    // served by this canister, so the caller() only gets public datums.
+    // Hidden ones need a capability link or an authenticated `get_datum`.
+    if !can_download(
+        path.strip_prefix('/').unwrap_or(&path),
+        capability.as_deref(),
+    ) {
+        return crate::assets::build_error(403, "hidden");
+    }
 
-    crate::assets::build_http_response(&crate::assets::url_decode(&path), encodings, 0)
+    // The gateway can't check a range against the certificate of the whole
+    // content, so ranges are only served on the raw domain.
+    let header = |header: &str| {
+        req.headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(header))
+            .map(|(_, value)| value.as_str())
+    };
+    let raw = header("Host").is_some_and(|host| host.split('.').any(|label| label == "raw"));
+    let mut response =
+        crate::assets::build_http_response(&path, encodings, header("Range"), raw, 0);
+    response.set_capability(capability);
+    response
 }
 
 #[query]
 fn http_request_streaming_callback(
     token: crate::assets::Token,
 ) -> crate::assets::StreamingCallbackHttpResponse {
-    let key = token.key.clone();
-    STATE.with(move |s| match s.data.borrow_mut().entry(key) {
-        Entry::Occupied(e) => {
-            // NOTE: the caller() is not the same because of limitations of II.
-            assert!(!e.get().hidden || caller() == e.get().owner);
+    // NOTE: as in `http_request`, the caller() is anonymous, only the
+    // capability of the token gets hidden datums.
+    let key = token.key.strip_prefix('/').unwrap_or(&token.key);
+    let capability = token.capability.clone();
+    if !can_download(key, capability.as_deref()) {
+        return crate::assets::StreamingCallbackHttpResponse::default();
+    }
+    let mut response = crate::assets::http_request_streaming_callback(token);
+    response.set_capability(capability);
+    response
+}
+
+/// Whether the caller, or anyone with the `capability` link token, may
+/// download the asset for `key`, which need not be a record.
+fn can_download(key: &str, capability: Option<&str>) -> bool {
+    let viewer = Viewer::caller();
+    STATE.with(|s| match RECORDS.get(&key.to_string()) {
+        Some(r) => {
+            viewer.can_download(&r)
+                || capability.is_some_and(|c| {
+                    crate::links::check(&s.secret.borrow(), key, r.link_epoch, viewer.now, c)
+                })
         }
-        Entry::Vacant(_e) => {}
-    });
-    crate::assets::http_request_streaming_callback(token)
+        None => true,
+    })
 }
 
+/// Notarizes a datum.  A hidden datum is revealed at `reveal_at`, if given.
 #[update]
-fn notarize(datum: Datum, description: String, hidden: bool) -> Option<RecordResult> {
-    assert!(description.len() <= MAX_DESCRIPTION_LENGTH);
-    let hash = crate::assets::hash_bytes(&datum.content);
-    let key = hex::encode(hash);
-    STATE.with(move |s| match s.data.borrow_mut().entry(key.clone()) {
-        Entry::Occupied(_e) => None,
-        Entry::Vacant(e) => {
-            let created = time() as u64;
-            crate::assets::do_put(
-                "/".to_owned() + &key,
-                hash,
-                datum.content_type.clone(),
-                datum.content.clone(),
-            );
-            let record = Record {
-                hash: key,
-                owner: caller(),
-                datum: Some(datum),
-                description: description,
+async fn notarize(
+    datum: Datum,
+    description: String,
+    hidden: bool,
+    reveal_at: Option<Timestamp>,
+) -> NotaryResult<RecordResult> {
+    check_description(&description)?;
+    ensure_secret().await;
+    let mut hashers = datum_hashers();
+    for hasher in hashers.iter_mut() {
+        hasher.update(&datum.content);
+    }
+    insert_datum(
+        datum_digests(hashers),
+        datum.content_type,
+        &datum.content,
+        description,
+        hidden,
+        reveal_at,
+    )
+}
+
+fn datum_hashers() -> Vec<Hasher> {
+    DATUM_ALGORITHMS.iter().map(|a| a.hasher()).collect()
+}
+
+fn datum_digests(hashers: Vec<Hasher>) -> Vec<TaggedHash> {
+    DATUM_ALGORITHMS
+        .iter()
+        .zip(hashers)
+        .map(|(algorithm, hasher)| TaggedHash {
+            algorithm: *algorithm,
+            hash: hex::encode(hasher.finalize()),
+        })
+        .collect()
+}
+
+/// Creates the record for a datum with the given `datum_digests`, storing
+/// the asset at the same time so that both appear atomically.  `reveal_at`
+/// only applies to a new record, not to an attestation of an existing one.
+fn insert_datum(
+    digests: Vec<TaggedHash>,
+    content_type: String,
+    content: &[u8],
+    description: String,
+    hidden: bool,
+    reveal_at: Option<Timestamp>,
+) -> NotaryResult<RecordResult> {
+    let key = digests[0].hash.clone();
+    let mut hash = crate::assets::Hash::default();
+    hex::decode_to_slice(&key, &mut hash).expect("a SHA-256 digest");
+    STATE.with(move |s| {
+        if let Some(existing) = digests
+            .iter()
+            .find_map(|d| resolve_digest(d.algorithm, &d.hash))
+        {
+            let mut record = RECORDS.get(&existing).unwrap();
+            attest(s, &mut record, description, hidden)?;
+            // A record created from a bare hash gets the datum of the first
+            // public attestation which provides it, unless it was taken down.
+            if record.datum.is_none() && record.takedown.is_none() && !hidden {
+                crate::assets::do_put(
+                    "/".to_owned() + &record.hash,
+                    hash,
+                    content_type.clone(),
+                    content,
+                );
+                record.datum = Some(DatumInfo { content_type });
+                for digest in digests {
+                    if !record.digests.contains(&digest) {
+                        record.digests.push(digest);
+                    }
+                }
+            }
+            index_record(s, &record);
+            log_event(&record, RecordEventKind::Attested);
+            RECORDS.insert(&existing, &record);
+            return Ok(to_result(&record, &Viewer::caller()));
+        }
+        // The key is taken by a record of another algorithm.
+        if RECORDS.contains_key(&key) {
+            return Err(NotaryError::AlreadyExists);
+        }
+        crate::assets::do_put("/".to_owned() + &key, hash, content_type.clone(), content);
+        let mut record = Record {
+            hash: key,
+            algorithm: HashAlgorithm::Sha256,
+            digests,
+            owner: caller(),
+            datum: Some(DatumInfo { content_type }),
+            hidden,
+            attestations: vec![],
+            grants: vec![],
+            reveal_at: None,
+            revealed: None,
+            link_epoch: 0,
+            previous_owners: vec![],
+            transfer_to: None,
+            takedown: None,
+        };
+        attest(s, &mut record, description, hidden)?;
+        set_reveal_at(&mut record, reveal_at);
+        Ok(insert_record(s, record))
+    })
+}
+
+/// Starts a chunked upload of a datum which is too large for a single
+/// `notarize` call.  Chunks are added with `upload_chunk` and the record is
+/// created by `commit_upload`.  Only authenticated principals can upload.
+/// Uploads aren't kept in stable memory, so those not committed before an
+/// upgrade have to be started again, as do those left idle for
+/// `UPLOAD_IDLE_EXPIRY_NANOS`.
+#[update]
+fn begin_upload(
+    content_type: String,
+    description: String,
+    hidden: bool,
+    reveal_at: Option<Timestamp>,
+) -> NotaryResult<UploadId> {
+    check_description(&description)?;
+    let now = time() as u64;
+    let owner = caller();
+    if owner == Principal::anonymous() {
+        return Err(NotaryError::Unauthorized);
+    }
+    STATE.with(|s| {
+        let mut uploads = s.uploads.borrow_mut();
+        expire_uploads(&mut uploads, now);
+        if uploads.values().filter(|u| u.owner == owner).count() >= MAX_OPEN_UPLOADS {
+            return Err(NotaryError::QuotaExceeded);
+        }
+        let mut next_upload_id = s.next_upload_id.borrow_mut();
+        let upload_id = *next_upload_id;
+        *next_upload_id += 1;
+        uploads.insert(
+            upload_id,
+            Upload {
+                owner,
+                content_type,
+                description,
                 hidden,
-                created,
-            };
-            let result = to_result(&record);
-            e.insert(record);
-            Some(result)
+                reveal_at,
+                content: vec![],
+                hashers: datum_hashers(),
+                last_active: now,
+            },
+        );
+        Ok(upload_id)
+    })
+}
+
+#[update]
+fn upload_chunk(upload_id: UploadId, chunk: ByteBuf) -> NotaryResult<()> {
+    let now = time() as u64;
+    let owner = caller();
+    STATE.with(|s| {
+        let mut uploads = s.uploads.borrow_mut();
+        expire_uploads(&mut uploads, now);
+        let (pending, owned) = uploads.values().fold((0, 0), |(pending, owned), u| {
+            let length = u.content.len();
+            (
+                pending + length,
+                owned + if u.owner == owner { length } else { 0 },
+            )
+        });
+        let upload = uploads.get_mut(&upload_id).ok_or(NotaryError::NotFound)?;
+        if owner != upload.owner {
+            return Err(NotaryError::NotOwner);
+        }
+        if upload.content.len() + chunk.len() > MAX_UPLOAD_SIZE
+            || owned + chunk.len() > MAX_PRINCIPAL_UPLOAD_BYTES
+            || pending + chunk.len() > MAX_PENDING_UPLOAD_BYTES
+        {
+            return Err(NotaryError::QuotaExceeded);
         }
+        for hasher in upload.hashers.iter_mut() {
+            hasher.update(&chunk);
+        }
+        upload.content.extend_from_slice(&chunk);
+        upload.last_active = now;
+        Ok(())
     })
 }
 
+fn expire_uploads(uploads: &mut HashMap<UploadId, Upload>, now: Timestamp) {
+    uploads.retain(|_, u| now.saturating_sub(u.last_active) < UPLOAD_IDLE_EXPIRY_NANOS);
+}
+
+/// Finishes a chunked upload.  The SHA-256 over all the chunks must match
+/// `hex_sha256` for the record to be created, otherwise the upload is
+/// discarded.
 #[update]
-fn notarize_hash(hex_sha256: String, description: String) -> Option<RecordResult> {
-    assert!(description.len() <= MAX_DESCRIPTION_LENGTH);
-    let _hash = hex::decode(hex_sha256.clone()).unwrap();
-    assert!(_hash.len() == 32);
-    STATE.with(
-        move |s| match s.data.borrow_mut().entry(hex_sha256.clone()) {
-            Entry::Occupied(_e) => None,
-            Entry::Vacant(e) => {
-                let created = time() as u64;
-                let record = Record {
-                    hash: hex_sha256,
-                    owner: caller(),
-                    datum: None,
-                    description: description,
-                    hidden: false,
-                    created,
-                };
-                let result = to_result(&record);
-                e.insert(record);
-                Some(result)
-            }
-        },
+async fn commit_upload(upload_id: UploadId, hex_sha256: String) -> NotaryResult<RecordResult> {
+    ensure_secret().await;
+    let upload = STATE.with(|s| {
+        let mut uploads = s.uploads.borrow_mut();
+        match uploads.get(&upload_id) {
+            None => Err(NotaryError::NotFound),
+            Some(upload) if caller() != upload.owner => Err(NotaryError::NotOwner),
+            Some(_) => Ok(uploads.remove(&upload_id).unwrap()),
+        }
+    })?;
+    let digests = datum_digests(upload.hashers);
+    if hex_sha256.to_lowercase() != digests[0].hash {
+        return Err(NotaryError::InvalidHash);
+    }
+    insert_datum(
+        digests,
+        upload.content_type,
+        &upload.content,
+        upload.description,
+        upload.hidden,
+        upload.reveal_at,
     )
 }
 
+/// Notarizes a hex encoded digest, by default a SHA-256.
 #[update]
-fn reveal(hex_sha256: String) -> Option<RecordResult> {
-    STATE.with(
-        move |s| match s.data.borrow_mut().entry(hex_sha256.clone()) {
-            Entry::Occupied(mut e) => {
-                if caller() == e.get().owner {
-                    e.get_mut().hidden = false;
-                }
-                Some(to_result(e.get()))
-            }
-            Entry::Vacant(_e) => None,
-        },
-    )
+async fn notarize_hash(
+    hex_hash: String,
+    description: String,
+    algorithm: Option<HashAlgorithm>,
+) -> NotaryResult<RecordResult> {
+    check_description(&description)?;
+    ensure_secret().await;
+    let algorithm = algorithm.unwrap_or_default();
+    let hash = hex::decode(hex_hash).map_err(|_| NotaryError::InvalidHash)?;
+    if hash.len() != algorithm.digest_length() {
+        return Err(NotaryError::InvalidHash);
+    }
+    let key = hex::encode(hash);
+    STATE.with(move |s| {
+        if let Some(existing) = resolve_digest(algorithm, &key) {
+            let mut record = RECORDS.get(&existing).unwrap();
+            attest(s, &mut record, description, false)?;
+            index_record(s, &record);
+            log_event(&record, RecordEventKind::Attested);
+            RECORDS.insert(&existing, &record);
+            return Ok(to_result(&record, &Viewer::caller()));
+        }
+        // The key is taken by a record of another algorithm.
+        if RECORDS.contains_key(&key) {
+            return Err(NotaryError::AlreadyExists);
+        }
+        let mut record = Record {
+            hash: key.clone(),
+            algorithm,
+            digests: vec![TaggedHash {
+                algorithm,
+                hash: key,
+            }],
+            owner: caller(),
+            datum: None,
+            hidden: false,
+            attestations: vec![],
+            grants: vec![],
+            reveal_at: None,
+            revealed: None,
+            link_epoch: 0,
+            previous_owners: vec![],
+            transfer_to: None,
+            takedown: None,
+        };
+        attest(s, &mut record, description, false)?;
+        Ok(insert_record(s, record))
+    })
 }
 
-#[query(guard = "is_authorized")]
-fn get_datum(hash: Hash) -> Option<Datum> {
-    STATE.with(|s| match s.data.borrow().get(&hash) {
-        Some(r) => r.datum.clone(),
-        None => None,
+/// Makes the caller's hidden attestation public and, for the owner of the
+/// record, the datum as well.
+#[update]
+fn reveal(hash: Hash) -> NotaryResult<RecordResult> {
+    update_record(&hash, |s, record| {
+        let caller = caller();
+        if caller != record.owner && record.attestations.iter().all(|a| a.owner != caller) {
+            return Err(NotaryError::NotOwner);
+        }
+        if unhide(record, &caller) {
+            index_record(s, record);
+            log_event(record, RecordEventKind::Revealed);
+        }
+        Ok(to_result(record, &Viewer::caller()))
     })
 }
 
-#[query(guard = "is_authorized")]
-fn get_data() -> Vec<RecordResult> {
+/// Applies `f` to the record for `hash`, saving it unless `f` fails.
+fn update_record<T>(
+    hash: &str,
+    f: impl FnOnce(&State, &mut Record) -> NotaryResult<T>,
+) -> NotaryResult<T> {
     STATE.with(|s| {
-        s.data
-            .borrow()
-            .iter()
-            .map(|(_key, record)| to_result(&record))
-            .collect::<Vec<_>>()
+        let key = resolve(hash).ok_or(NotaryError::NotFound)?;
+        let mut record = RECORDS.get(&key).ok_or(NotaryError::NotFound)?;
+        let result = f(s, &mut record)?;
+        RECORDS.insert(&key, &record);
+        Ok(result)
     })
 }
 
-#[query]
-fn search(search_terms: SearchTerms) -> Vec<RecordResult> {
-    STATE.with(|s| {
-        let matcher = s.matcher.borrow();
-        let mut matches = s
-            .data
-            .borrow()
-            .iter()
-            .map(|(key, record)| {
-                (
-                    matcher.fuzzy_match(&key.to_lowercase(), &search_terms.to_lowercase()),
-                    matcher.fuzzy_match(
-                        &record.description.to_lowercase(),
-                        &search_terms.to_lowercase(),
-                    ),
-                    to_result(&record),
-                )
-            })
-            .collect::<Vec<_>>();
-        matches.sort_by(|a, b| match (a.0, b.0) {
-            (Some(a_score), Some(b_score)) => a_score.cmp(&b_score).reverse(),
-            (None, Some(_)) => Ordering::Greater,
-            (Some(_), None) => Ordering::Less,
-            (None, None) => Ordering::Equal,
+/// Applies `f` to the record for `hash` if the caller owns it, see
+/// `update_record`.
+fn update_owned_record<T>(
+    hash: &str,
+    f: impl FnOnce(&State, &mut Record) -> NotaryResult<T>,
+) -> NotaryResult<T> {
+    update_record(hash, |s, record| {
+        if caller() != record.owner {
+            return Err(NotaryError::NotOwner);
+        }
+        f(s, record)
+    })
+}
+
+/// Lets `grantee` read the hidden record and its datum, until `expires` if
+/// given.  Granting again replaces the expiry.
+#[update]
+fn grant_access(
+    hash: Hash,
+    grantee: Principal,
+    expires: Option<Timestamp>,
+) -> NotaryResult<RecordResult> {
+    let now = time() as u64;
+    update_owned_record(&hash, |_, record| {
+        record
+            .grants
+            .retain(|g| g.grantee != grantee && g.is_active(now));
+        record.grants.push(Grant {
+            grantee,
+            granted: now,
+            expires,
         });
-        let end = std::cmp::min(MAX_SEARCH_RESULTS, matches.len());
-        let mut top_data: Vec<(Option<i64>, RecordResult)> =
-            matches[..end].iter().map(|x| (x.0, x.2.clone())).collect();
-        matches.sort_by(|a, b| match (a.1, b.1) {
-            (Some(a_score), Some(b_score)) => a_score.cmp(&b_score).reverse(),
-            (None, Some(_)) => Ordering::Greater,
-            (Some(_), None) => Ordering::Less,
-            (None, None) => Ordering::Equal,
+        Ok(to_result(record, &Viewer::caller()))
+    })
+}
+
+/// Schedules the reveal of a hidden record at `reveal_at`, replacing any
+/// previous schedule, or cancels it with `None`.  A time in the past reveals
+/// the record with the next heartbeat.
+#[update]
+fn schedule_reveal(hash: Hash, reveal_at: Option<Timestamp>) -> NotaryResult<RecordResult> {
+    update_owned_record(&hash, |_, record| {
+        set_reveal_at(record, reveal_at);
+        Ok(to_result(record, &Viewer::caller()))
+    })
+}
+
+/// Draws the canister secret from the management canister's randomness.
+async fn ensure_secret() {
+    if STATE.with(|s| !s.secret.borrow().is_empty()) {
+        return;
+    }
+    let (bytes,): (Vec<u8>,) =
+        ic_cdk::api::call::call(Principal::management_canister(), "raw_rand", ())
+            .await
+            .unwrap_or_else(|(_, message)| ic_cdk::trap(&format!("raw_rand failed: {}", message)));
+    STATE.with(|s| {
+        // Another call may have set it while this one was waiting.
+        let mut secret = s.secret.borrow_mut();
+        if secret.is_empty() {
+            *secret = bytes;
+        }
+    });
+}
+
+/// Returns a capability link token which lets anyone download the hidden
+/// datum of the record until `expires`, by adding `?token=<token>` to its URL
+/// or sending it in the `X-Capability-Token` header.
+#[update]
+async fn create_link(hash: Hash, expires: Timestamp) -> NotaryResult<String> {
+    update_owned_record(&hash, |_, _| Ok(()))?;
+    ensure_secret().await;
+    update_owned_record(&hash, |s, record| {
+        Ok(crate::links::mint(
+            &s.secret.borrow(),
+            &record.hash,
+            record.link_epoch,
+            expires,
+        ))
+    })
+}
+
+/// Offers the record to `recipient`, who becomes the owner with
+/// `accept_transfer`.  A new offer replaces a pending one.
+#[update]
+fn offer_transfer(hash: Hash, recipient: Principal) -> NotaryResult<RecordResult> {
+    update_owned_record(&hash, |_, record| {
+        if recipient == record.owner {
+            return Err(NotaryError::AlreadyExists);
+        }
+        if recipient == Principal::anonymous() {
+            return Err(NotaryError::Unauthorized);
+        }
+        record.transfer_to = Some(recipient);
+        Ok(to_result(record, &Viewer::caller()))
+    })
+}
+
+/// Makes the caller the owner of a record offered to it.  The grants and
+/// capability links of the previous owner are dropped.
+#[update]
+fn accept_transfer(hash: Hash) -> NotaryResult<RecordResult> {
+    let caller = caller();
+    update_record(&hash, |s, record| {
+        if record.transfer_to.as_ref() != Some(&caller) {
+            return Err(NotaryError::NotFound);
+        }
+        record.transfer_to = None;
+        let previous = std::mem::replace(&mut record.owner, caller);
+        record.previous_owners.push(PreviousOwner {
+            owner: previous,
+            until: time() as u64,
         });
-        let mut top_descriptions: Vec<(Option<i64>, RecordResult)> =
-            matches[..end].iter().map(|x| (x.1, x.2.clone())).collect();
-        top_data.append(&mut top_descriptions);
-        let mut uniques = HashSet::new();
-        top_data.retain(|x| x.0.is_some() && uniques.insert(x.1.hash.clone()));
-        top_data.sort_by(|a, b| match (a.0, b.0) {
-            (Some(a_score), Some(b_score)) => a_score.cmp(&b_score).reverse(),
-            _ => Ordering::Equal, // Not happening.
+        record.grants.clear();
+        record.link_epoch += 1;
+        index_record(s, record);
+        log_event(record, RecordEventKind::Transferred);
+        Ok(to_result(record, &Viewer::caller()))
+    })
+}
+
+/// Withdraws, for the owner, or declines, for the recipient, a transfer.
+#[update]
+fn cancel_transfer(hash: Hash) -> NotaryResult<RecordResult> {
+    let caller = caller();
+    update_record(&hash, |_, record| {
+        let recipient = record.transfer_to.as_ref().ok_or(NotaryError::NotFound)?;
+        if caller != record.owner && caller != *recipient {
+            return Err(NotaryError::NotOwner);
+        }
+        record.transfer_to = None;
+        Ok(to_result(record, &Viewer::caller()))
+    })
+}
+
+/// Deletes the datum of a record, for its owner, an admin or a moderator,
+/// e.g. for an erasure request.  The record stays as a certified tombstone
+/// and its URL returns `410 Gone` with the reason.
+#[update]
+fn take_down(hash: Hash, reason: String) -> NotaryResult<RecordResult> {
+    check_description(&reason)?;
+    let caller = caller();
+    let authorized = is_authorized(&[Role::Admin, Role::Moderator]).is_ok();
+    update_record(&hash, |s, record| {
+        if caller != record.owner && !authorized {
+            return Err(NotaryError::NotOwner);
+        }
+        if record.takedown.is_some() {
+            return Err(NotaryError::AlreadyExists);
+        }
+        crate::assets::do_takedown("/".to_owned() + &record.hash, &reason);
+        record.datum = None;
+        record.takedown = Some(Takedown {
+            by: caller,
+            at: time() as u64,
+            reason,
         });
-        let end = std::cmp::min(MAX_SEARCH_RESULTS, top_data.len());
-        let top_data: Vec<RecordResult> = top_data[..end].iter().map(|x| x.1.clone()).collect();
-        top_data
+        index_record(s, record);
+        log_event(record, RecordEventKind::Deleted);
+        Ok(to_result(record, &Viewer::caller()))
+    })
+}
+
+/// Invalidates all the capability links of the record.
+#[update]
+fn revoke_links(hash: Hash) -> NotaryResult<RecordResult> {
+    update_owned_record(&hash, |_, record| {
+        record.link_epoch += 1;
+        Ok(to_result(record, &Viewer::caller()))
+    })
+}
+
+#[update]
+fn revoke_access(hash: Hash, grantee: Principal) -> NotaryResult<RecordResult> {
+    update_owned_record(&hash, |_, record| {
+        record.grants.retain(|g| g.grantee != grantee);
+        Ok(to_result(record, &Viewer::caller()))
+    })
+}
+
+/// Returns the record with a certificate and a witness proving that the
+/// canister holds it, which can be checked against the IC root key.  Hidden
+/// descriptions are redacted, the receipt still proves their hashes.
+#[query]
+fn get_receipt(hash: Hash) -> NotaryResult<Receipt> {
+    let viewer = Viewer::caller();
+    let key = resolve(&hash).ok_or(NotaryError::NotFound)?;
+    let record = RECORDS
+        .get(&key)
+        .filter(|r| viewer.can_see(r))
+        .ok_or(NotaryError::NotFound)?;
+    // Not yet certified again after an upgrade.
+    if !crate::receipts::is_certified(&key) {
+        return Err(NotaryError::Rebuilding);
+    }
+    Ok(crate::receipts::build_receipt(to_result(&record, &viewer)))
+}
+
+#[query]
+fn get_datum(hash: Hash) -> NotaryResult<Datum> {
+    let viewer = Viewer::caller();
+    let key = resolve(&hash).ok_or(NotaryError::NotFound)?;
+    let record = RECORDS.get(&key).ok_or(NotaryError::NotFound)?;
+    if !viewer.can_download(&record) {
+        return Err(NotaryError::Unauthorized);
+    }
+    let datum = record.datum.as_ref().ok_or(NotaryError::NotFound)?;
+    Ok(Datum {
+        content_type: datum.content_type.clone(),
+        content: crate::assets::do_get(&("/".to_owned() + &record.hash))
+            .ok_or(NotaryError::NotFound)?,
     })
 }
 
-fn is_authorized() -> Result<(), String> {
-    crate::assets::is_authorized()
+/// Exports the records matching the query of `request`, by default oldest
+/// first.
+#[query]
+fn get_data(request: SearchRequest) -> NotaryResult<SearchPage> {
+    is_authorized(&[Role::Admin, Role::Auditor])?;
+    search_records(request, SortOrder::CreatedAscending)
 }
 
+/// Returns the change feed from `cursor` on, scanning at most `limit` events.
+/// Events of records hidden from the caller are skipped.  Of the records
+/// which no longer exist, only authorized callers and the owners see all
+/// events, others only the deletion of those which were public, without who
+/// deleted them.  So a page can have fewer events, even none, before the end
+/// of the feed.
+#[query]
+fn get_updates(cursor: u64, limit: Option<u32>) -> NotaryResult<UpdatesPage> {
+    let limit = limit.map_or(MAX_UPDATES_PAGE, |l| {
+        std::cmp::min(l as usize, MAX_UPDATES_PAGE)
+    });
+    let viewer = Viewer::caller();
+    let start = std::cmp::min(cursor, EVENTS.len());
+    let events = EVENTS
+        .range(&start)
+        .take(limit)
+        .map(|(_, e)| e)
+        .collect::<Vec<_>>();
+    let end = start + events.len() as u64;
+    let updates = events
+        .iter()
+        .filter_map(|e| match RECORDS.get(&e.event.hash) {
+            Some(r) if !viewer.can_see(&r) => None,
+            Some(r) => Some(UpdatedRecordResult {
+                event: e.event.clone(),
+                record: Some(to_result(&r, &viewer)),
+            }),
+            None if viewer.authorized || viewer.principal == e.owner => Some(UpdatedRecordResult {
+                event: e.event.clone(),
+                record: None,
+            }),
+            None if !e.hidden && e.event.kind == RecordEventKind::Deleted => {
+                Some(UpdatedRecordResult {
+                    event: RecordEvent {
+                        principal: Principal::anonymous(),
+                        ..e.event.clone()
+                    },
+                    record: None,
+                })
+            }
+            None => None,
+        })
+        .collect::<Vec<_>>();
+    Ok(UpdatesPage {
+        next_cursor: end,
+        updates,
+    })
+}
+
+/// Returns a page of the records matching the query of `request`.  The
+/// filters of the query are applied first.  By relevance, records are ranked
+/// by the BM25 score of the terms in the search index plus the best fuzzy
+/// match of the terms against their digests and descriptions.
+fn search_records(request: SearchRequest, default_sort: SortOrder) -> NotaryResult<SearchPage> {
+    let query = crate::query::Query::parse(&request.query).map_err(NotaryError::InvalidQuery)?;
+    let sort = match request.sort.unwrap_or(default_sort) {
+        SortOrder::Relevance if query.terms.is_empty() => SortOrder::CreatedDescending,
+        sort => sort,
+    };
+    let limit = request.limit.map_or(MAX_SEARCH_RESULTS, |l| {
+        std::cmp::min(l as usize, MAX_SEARCH_PAGE)
+    });
+    let cursor = match request.cursor {
+        Some(cursor) => Some(parse_cursor(&cursor)?),
+        None => None,
+    };
+    let terms = query.terms.join(" ");
+    let viewer = Viewer::caller();
+    STATE.with(|s| {
+        // Each match has a sort key, the bits of its score or its priority
+        // date, which together with the record key is its position.
+        let mut matches = if query.terms.is_empty() {
+            RECORDS
+                .iter()
+                .map(|(_, record)| record)
+                .filter(|record| viewer.can_see(record) && query.matches(record))
+                .map(|record| (record.priority().created, record))
+                .collect::<Vec<_>>()
+        } else {
+            // The search index is incomplete until rebuilt after an upgrade.
+            if s.rebuild.borrow().is_some() {
+                return Err(NotaryError::Rebuilding);
+            }
+            let matcher = s.matcher.borrow();
+            let scores = s.index.borrow().score(&query.terms, &matcher);
+            scores
+                .into_iter()
+                .filter_map(|(key, score)| {
+                    let record = RECORDS
+                        .get(&key)
+                        .filter(|r| viewer.can_see(r) && query.matches(r))?;
+                    if sort != SortOrder::Relevance {
+                        return Some((record.priority().created, record));
+                    }
+                    let fuzzy = record
+                        .digests
+                        .iter()
+                        .filter_map(|d| matcher.fuzzy_match(&d.hash, &terms))
+                        .chain(record.public_attestations().filter_map(|a| {
+                            matcher.fuzzy_match(&a.description.to_lowercase(), &terms)
+                        }))
+                        .max()
+                        .unwrap_or(0);
+                    // The bits of non-negative floats sort like the floats.
+                    let score = score + fuzzy.max(0) as f64 / FUZZY_SCORE_SCALE;
+                    Some((score.to_bits(), record))
+                })
+                .collect::<Vec<_>>()
+        };
+        let order = |a: (u64, &str), b: (u64, &str)| match sort {
+            SortOrder::CreatedAscending => a.cmp(&b),
+            _ => b.0.cmp(&a.0).then_with(|| a.1.cmp(b.1)),
+        };
+        matches.sort_by(|a, b| order((a.0, a.1.hash.as_str()), (b.0, b.1.hash.as_str())));
+        let start = match cursor {
+            Some((key, ref hash)) => matches
+                .iter()
+                .position(|m| {
+                    order((m.0, m.1.hash.as_str()), (key, hash.as_str())) == Ordering::Greater
+                })
+                .unwrap_or(matches.len()),
+            None => 0,
+        };
+        let page = &matches[start..std::cmp::min(start + limit, matches.len())];
+        Ok(SearchPage {
+            results: page
+                .iter()
+                .map(|(_, record)| to_result(record, &viewer))
+                .collect(),
+            next_cursor: match page.last() {
+                Some((key, record)) if start + page.len() < matches.len() => {
+                    Some(format!("{:016x}:{}", key, record.hash))
+                }
+                _ => None,
+            },
+            total: matches.len() as u64,
+        })
+    })
+}
+
+/// Parses a cursor made by `search_records`: the hex sort key and the record
+/// key of the last result of the previous page.
+fn parse_cursor(cursor: &str) -> NotaryResult<(u64, Hash)> {
+    let invalid = || NotaryError::InvalidQuery(format!("invalid cursor {}", cursor));
+    let i = cursor.find(':').ok_or_else(invalid)?;
+    let key = u64::from_str_radix(&cursor[..i], 16).map_err(|_| invalid())?;
+    Ok((key, cursor[i + 1..].to_string()))
+}
+
+/// Searches the records, by default by relevance.
+#[query]
+fn search(request: SearchRequest) -> NotaryResult<SearchPage> {
+    search_records(request, SortOrder::Relevance)
+}
+
+fn is_authorized(roles: &[Role]) -> NotaryResult<()> {
+    crate::assets::is_authorized(roles)
+}
+
+/// Drops the indexes of the records, which must have been removed.  The
+/// change feed is append-only, it survives a clear.
 fn do_clear() {
+    DIGESTS.clear();
+    REVEALS.clear();
+    STATE.with(|s| {
+        s.index.borrow_mut().clear();
+        s.uploads.borrow_mut().clear();
+        s.rebuild.replace(None);
+    });
+    crate::receipts::do_clear();
+}
+
+fn log_admin_event(kind: AdminEventKind, principal: Principal) {
+    ADMIN_LOG.insert(
+        &ADMIN_LOG.len(),
+        &AdminEvent {
+            kind,
+            principal,
+            timestamp: time() as u64,
+        },
+    );
+}
+
+/// Requests to clear all the records and assets, replacing any pending
+/// request.  Another admin confirms it with `confirm_clear`, or the
+/// requester after `CLEAR_DELAY_NANOS`.
+#[update]
+fn clear() -> NotaryResult<PendingClear> {
+    is_authorized(&[Role::Admin])?;
+    let requested_by = caller();
+    let requested = time() as u64;
+    let mut nonce = sha2::Sha256::new();
+    nonce.update(b"clear");
+    nonce.update(requested.to_be_bytes());
+    nonce.update(requested_by.as_slice());
+    let pending = PendingClear {
+        nonce: hex::encode(&nonce.finalize()[..8]),
+        requested_by,
+        requested,
+        confirmable: requested + CLEAR_DELAY_NANOS,
+        expires: requested + CLEAR_REQUEST_EXPIRY_NANOS,
+    };
     STATE.with(|s| {
-        s.data.borrow_mut().clear();
+        s.pending_clear.replace(Some(pending.clone()));
+        log_admin_event(AdminEventKind::ClearRequested, requested_by);
+    });
+    Ok(pending)
+}
+
+#[update]
+fn cancel_clear() -> NotaryResult<()> {
+    is_authorized(&[Role::Admin])?;
+    STATE.with(|s| {
+        s.pending_clear.take().ok_or(NotaryError::NotFound)?;
+        log_admin_event(AdminEventKind::ClearCancelled, caller());
+        Ok(())
+    })
+}
+
+/// Clears all the records and assets as requested with `clear`, keeping them
+/// in a snapshot which `undo_clear` restores for `CLEAR_RETENTION_NANOS`.
+/// The snapshot of any earlier clear is dropped.
+#[update]
+fn confirm_clear(nonce: String) -> NotaryResult<()> {
+    is_authorized(&[Role::Admin])?;
+    let now = time() as u64;
+    STATE.with(|s| {
+        {
+            let pending = s.pending_clear.borrow();
+            let pending = pending
+                .as_ref()
+                .filter(|p| p.nonce == nonce && now < p.expires)
+                .ok_or(NotaryError::NotFound)?;
+            if pending.requested_by == caller() && now < pending.confirmable {
+                return Err(NotaryError::Unauthorized);
+            }
+        }
+        s.pending_clear.replace(None);
+        for (_, record) in RECORDS.iter() {
+            log_event(&record, RecordEventKind::Deleted);
+        }
+        SNAPSHOT_RECORDS.clear();
+        RECORDS.swap(&SNAPSHOT_RECORDS);
+        do_clear();
+        crate::assets::take_snapshot();
+        s.snapshot.replace(Some(Snapshot { taken: now }));
+        log_admin_event(AdminEventKind::ClearConfirmed, caller());
+        Ok(())
+    })
+}
+
+/// Restores the records and assets removed by the last clear.  The records
+/// notarized since with the same key are merged into them, see
+/// `merge_restored`, unless one has another algorithm, which fails the
+/// undo.  Restored records are logged as created in the change feed.
+#[update]
+fn undo_clear() -> NotaryResult<()> {
+    is_authorized(&[Role::Admin])?;
+    STATE.with(|s| {
+        if s.snapshot.borrow().is_none() {
+            return Err(NotaryError::NotFound);
+        }
+        // A record of another algorithm with the same key can't be merged.
+        if SNAPSHOT_RECORDS.iter().any(|(key, r)| {
+            RECORDS
+                .get(&key)
+                .is_some_and(|current| current.algorithm != r.algorithm)
+        }) {
+            return Err(NotaryError::AlreadyExists);
+        }
+        s.snapshot.replace(None);
+        for (key, mut record) in SNAPSHOT_RECORDS.iter() {
+            if let Some(mut current) = RECORDS.get(&key) {
+                set_reveal_at(&mut current, None);
+                merge_restored(&mut record, current);
+            }
+            if let Some(t) = record.reveal_at.take() {
+                set_reveal_at(&mut record, Some(t));
+            }
+            index_record(s, &record);
+            log_event(&record, RecordEventKind::Created);
+            RECORDS.insert(&key, &record);
+        }
+        SNAPSHOT_RECORDS.clear();
+        crate::assets::restore_snapshot();
+        log_admin_event(AdminEventKind::ClearUndone, caller());
+        Ok(())
     })
 }
 
-#[update(guard = "is_authorized")]
-fn clear() {
-    do_clear();
-    crate::assets::do_clear();
+/// Adds to a record restored by `undo_clear` what was notarized with the
+/// same key since the clear: the attestations of other principals and the
+/// datum, if the restored record has none and wasn't taken down.
+fn merge_restored(record: &mut Record, current: Record) {
+    if record.datum.is_none() && record.takedown.is_none() {
+        record.datum = current.datum;
+        for digest in current.digests {
+            if !record.digests.contains(&digest) {
+                record.digests.push(digest);
+            }
+        }
+    }
+    for attestation in current.attestations {
+        if record
+            .attestations
+            .iter()
+            .all(|a| a.owner != attestation.owner)
+        {
+            record.attestations.push(attestation);
+        }
+    }
+    record.attestations.sort_by_key(|a| a.created);
+}
+
+#[query]
+fn get_clear_status() -> NotaryResult<ClearStatus> {
+    is_authorized(&[Role::Admin, Role::Auditor])?;
+    let now = time() as u64;
+    Ok(STATE.with(|s| ClearStatus {
+        pending: s.pending_clear.borrow().clone().filter(|p| now < p.expires),
+        undo_until: s
+            .snapshot
+            .borrow()
+            .as_ref()
+            .map(|snapshot| snapshot.taken + CLEAR_RETENTION_NANOS),
+    }))
+}
+
+/// Reports how much storage the chunk store saves.
+#[query]
+fn get_storage_stats() -> NotaryResult<StorageStats> {
+    is_authorized(&[Role::Admin, Role::Auditor])?;
+    Ok(crate::chunks::stats())
+}
+
+#[query]
+fn get_admin_log() -> NotaryResult<Vec<AdminEvent>> {
+    is_authorized(&[Role::Admin, Role::Auditor])?;
+    Ok(ADMIN_LOG.iter().map(|(_, event)| event).collect())
+}
+
+/// Drops the snapshot of the last clear once its retention window passed.
+fn expire_snapshot(s: &State, now: Timestamp) {
+    let expired = s
+        .snapshot
+        .borrow()
+        .as_ref()
+        .is_some_and(|snapshot| snapshot.taken + CLEAR_RETENTION_NANOS <= now);
+    if !expired {
+        return;
+    }
+    s.snapshot.replace(None);
+    SNAPSHOT_RECORDS.clear();
+    crate::assets::drop_snapshot();
+    log_admin_event(AdminEventKind::SnapshotExpired, ic_cdk::id());
+}
+
+/// Certifies and indexes the next records after an upgrade, which keeps the
+/// records but not the certification tree and the search index.
+fn rebuild_records(s: &State) {
+    let cursor = match s.rebuild.borrow().clone() {
+        Some(cursor) => cursor,
+        None => return,
+    };
+    let mut records = RECORDS.range(&cursor);
+    for (_, record) in records.by_ref().take(MAX_REBUILDS_PER_HEARTBEAT) {
+        certify_record(s, &record);
+    }
+    s.rebuild.replace(records.next().map(|(key, _)| key));
+}
+
+/// Rebuilds the indexes after an upgrade, reveals the records whose
+/// scheduled time has passed, expires the snapshot of the last clear and
+/// compresses datums.
+#[export_name = "canister_heartbeat"]
+fn heartbeat() {
+    ic_cdk::setup();
+    let now = time() as u64;
+    crate::assets::rebuild_assets();
+    STATE.with(|s| {
+        rebuild_records(s);
+        expire_snapshot(s, now);
+        for _ in 0..MAX_REVEALS_PER_HEARTBEAT {
+            let (t, key) = match REVEALS.iter().next() {
+                Some(((t, key), ())) if t <= now => (t, key),
+                _ => break,
+            };
+            let mut record = match RECORDS.get(&key) {
+                Some(record) => record,
+                None => {
+                    REVEALS.remove(&(t, key));
+                    continue;
+                }
+            };
+            let owner = record.owner;
+            if unhide(&mut record, &owner) {
+                index_record(s, &record);
+                log_event_as(&record, RecordEventKind::Revealed, owner);
+            }
+            RECORDS.insert(&key, &record);
+        }
+    });
+    // In a call of its own, as a compression step which traps must not roll
+    // back the reveals.
+    if crate::assets::schedule_compression() {
+        ic_cdk::block_on(async {
+            let _: ic_cdk::api::call::CallResult<()> =
+                ic_cdk::call(ic_cdk::id(), "compress_next", ()).await;
+            crate::assets::compression_done();
+        });
+    }
 }
 
 #[init]
 fn init() {
-    do_clear();
+    crate::stable::init();
     crate::assets::init();
 }
 
+/// Saves what isn't in the maps in stable memory, which stay as they are.
 #[pre_upgrade]
 fn pre_upgrade() {
-    let stable_state = STATE.with(|s| StableState {
-        data: s.data.take(),
+    let stable_state = STATE.with(|s| crate::upgrade::StableState {
+        secret: ByteBuf::from(s.secret.take()),
         assets: crate::assets::pre_upgrade(),
+        pending_clear: s.pending_clear.take(),
+        snapshot: s.snapshot.take(),
     });
-    ic_cdk::storage::stable_save((stable_state,)).expect("failed to save stable state");
+    crate::upgrade::save(&stable_state);
 }
 
+/// Restores the saved state.  The certification tree and the search index
+/// are rebuilt by the heartbeat, a bounded number of records at a time, and
+/// receipts and searches of records not yet indexed fail with `Rebuilding`.
 #[post_upgrade]
 fn post_upgrade() {
-    do_clear();
-    let (stable_state,): (StableState,) =
-        ic_cdk::storage::stable_restore().expect("failed to restore stable state");
+    let crate::upgrade::StableState {
+        secret,
+        assets,
+        pending_clear,
+        snapshot,
+    } = crate::upgrade::restore();
     STATE.with(|s| {
-        s.data.replace(stable_state.data);
-        crate::assets::post_upgrade(stable_state.assets);
+        s.secret.replace(secret.into_vec());
+        s.pending_clear.replace(pending_clear);
+        s.snapshot.replace(snapshot);
+        s.rebuild.replace(Some(Hash::new()));
     });
+    crate::assets::post_upgrade(assets);
 }
@@ -0,0 +1,71 @@
+//! Capability links let anyone holding the link download a hidden datum over
+//! HTTP, where the caller is always anonymous.  The token is signed with a
+//! canister secret and is scoped to a single record key, an expiry and the
+//! record's link epoch, which the owner bumps to revoke all its links.
+
+use dfnhack7_common::Timestamp;
+use hmac::{Hmac, Mac, NewMac};
+
+type HmacSha256 = Hmac<sha2::Sha256>;
+
+/// Domain separator for the signed link fields.
+const LINK_DOMAIN: &[u8] = b"dfnhack7-link";
+const MAC_LENGTH: usize = 32;
+
+fn mac(secret: &[u8], key: &str, epoch: u32, expires: Timestamp) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC takes any key length");
+    mac.update(LINK_DOMAIN);
+    mac.update(&(key.len() as u32).to_be_bytes());
+    mac.update(key.as_bytes());
+    mac.update(&epoch.to_be_bytes());
+    mac.update(&expires.to_be_bytes());
+    mac
+}
+
+/// Returns a URL safe token granting download of the record `key` until
+/// `expires`.
+pub fn mint(secret: &[u8], key: &str, epoch: u32, expires: Timestamp) -> String {
+    let mut token = expires.to_be_bytes().to_vec();
+    token.extend_from_slice(&mac(secret, key, epoch, expires).finalize().into_bytes());
+    base64::encode_config(token, base64::URL_SAFE_NO_PAD)
+}
+
+/// Whether `token` grants download of the record `key` at `now`.  No token
+/// is valid until the secret has been drawn.
+pub fn check(secret: &[u8], key: &str, epoch: u32, now: Timestamp, token: &str) -> bool {
+    if secret.is_empty() {
+        return false;
+    }
+    let token = match base64::decode_config(token, base64::URL_SAFE_NO_PAD) {
+        Ok(token) if token.len() == 8 + MAC_LENGTH => token,
+        _ => return false,
+    };
+    let mut expires = [0u8; 8];
+    expires.copy_from_slice(&token[..8]);
+    let expires = Timestamp::from_be_bytes(expires);
+    now < expires && mac(secret, key, epoch, expires).verify(&token[8..]).is_ok()
+}
+
+#[test]
+fn check_links() {
+    let secret = b"0123456789abcdef0123456789abcdef";
+    let token = mint(secret, "abc", 0, 1000);
+    assert!(check(secret, "abc", 0, 999, &token));
+    // Expired.
+    assert!(!check(secret, "abc", 0, 1000, &token));
+    // Other record, revoked, other secret.
+    assert!(!check(secret, "abd", 0, 999, &token));
+    assert!(!check(secret, "abc", 1, 999, &token));
+    assert!(!check(b"another secret", "abc", 0, 999, &token));
+    // Tampered expiry.
+    let forged = mint(b"another secret", "abc", 0, 2000);
+    let mut bytes = base64::decode_config(&token, base64::URL_SAFE_NO_PAD).unwrap();
+    bytes[..8].copy_from_slice(&2000u64.to_be_bytes());
+    let tampered = base64::encode_config(bytes, base64::URL_SAFE_NO_PAD);
+    assert!(!check(secret, "abc", 0, 1500, &tampered));
+    assert!(!check(secret, "abc", 0, 1500, &forged));
+    assert!(!check(secret, "abc", 0, 0, "not a token"));
+    // Anyone can sign with the empty secret of a fresh or migrated canister.
+    let unkeyed = mint(b"", "abc", 0, 1000);
+    assert!(!check(b"", "abc", 0, 999, &unkeyed));
+}
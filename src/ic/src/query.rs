@@ -0,0 +1,310 @@
+use dfnhack7_common::{Record, Timestamp};
+use ic_cdk::export::candid::Principal;
+
+/// A parsed search query: the terms ranked by the search index and the
+/// conditions a record must satisfy to be ranked at all.
+///
+/// The syntax is a list of words, `"quoted phrases"` and `operator:value`
+/// filters, any of which can be negated with a leading `-`:
+///
+/// * `owner:<principal>` notarized or attested by the principal,
+/// * `after:YYYY-MM-DD`, `before:YYYY-MM-DD` priority date (UTC) on or after,
+///   or before, the start of the day,
+/// * `type:<content type>` the datum's content type, `type:image/*` matches
+///   any image,
+/// * `has:datum` the content is stored, not only its hash,
+/// * `hidden:true`, `hidden:false`.
+#[derive(Debug, Default, PartialEq)]
+pub struct Query {
+    pub terms: Vec<String>,
+    pub conditions: Vec<Condition>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Condition {
+    pub negated: bool,
+    pub filter: Filter,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Filter {
+    Owner(Principal),
+    After(Timestamp),
+    Before(Timestamp),
+    ContentType(String),
+    HasDatum,
+    Hidden(bool),
+    /// A lowercase phrase which must occur in a public description.
+    Phrase(String),
+    /// A lowercase token which must occur in a public description or digest.
+    Word(String),
+}
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+/// The last year accepted in date filters, before timestamps in nanoseconds
+/// overflow in 2554.
+const MAX_YEAR: u32 = 2500;
+
+impl Query {
+    pub fn parse(text: &str) -> Result<Query, String> {
+        let mut query = Query::default();
+        let mut chars = text.chars().peekable();
+        loop {
+            while chars.peek().is_some_and(|c| c.is_whitespace()) {
+                chars.next();
+            }
+            if chars.peek().is_none() {
+                return Ok(query);
+            }
+            let negated = chars.peek() == Some(&'-');
+            if negated {
+                chars.next();
+            }
+            // Read an operator or word, then a quoted phrase or value.
+            let mut word = String::new();
+            while let Some(c) = chars.peek() {
+                if c.is_whitespace() || *c == '"' {
+                    break;
+                }
+                word.push(*c);
+                chars.next();
+            }
+            let mut quoted = None;
+            if chars.peek() == Some(&'"') {
+                chars.next();
+                let mut phrase = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some(c) => phrase.push(c),
+                        None => return Err("unterminated quote".to_string()),
+                    }
+                }
+                quoted = Some(phrase);
+            }
+            match (word.find(':'), quoted) {
+                (None, Some(phrase)) if word.is_empty() => {
+                    query.add_phrase(negated, &phrase);
+                }
+                (None, Some(_)) => return Err(format!("unexpected quote after {}", word)),
+                (None, None) => query.add_phrase(negated, &word),
+                (Some(i), quoted) => {
+                    let value = match quoted {
+                        Some(v) if i + 1 == word.len() => v,
+                        Some(_) => return Err(format!("unexpected quote after {}", word)),
+                        None => word[i + 1..].to_string(),
+                    };
+                    let filter = parse_filter(&word[..i], value.trim())?;
+                    query.conditions.push(Condition { negated, filter });
+                }
+            }
+        }
+    }
+
+    /// Adds words or a phrase: positive ones are also ranked, negated ones
+    /// only exclude records.
+    fn add_phrase(&mut self, negated: bool, phrase: &str) {
+        let tokens = crate::search::tokenize(phrase);
+        let filter = match tokens.len() {
+            0 => return,
+            1 => Filter::Word(tokens[0].clone()),
+            _ => Filter::Phrase(tokens.join(" ")),
+        };
+        if !negated {
+            self.terms.extend(tokens);
+        }
+        // Single positive words only rank, they need not all match.
+        if negated || matches!(filter, Filter::Phrase(_)) {
+            self.conditions.push(Condition { negated, filter });
+        }
+    }
+
+    pub fn matches(&self, record: &Record) -> bool {
+        self.conditions
+            .iter()
+            .all(|c| c.filter.matches(record) != c.negated)
+    }
+}
+
+fn parse_filter(operator: &str, value: &str) -> Result<Filter, String> {
+    match operator.to_lowercase().as_str() {
+        "owner" => Principal::from_text(value)
+            .map(Filter::Owner)
+            .map_err(|_| format!("invalid principal {}", value)),
+        "after" => parse_date(value).map(Filter::After),
+        "before" => parse_date(value).map(Filter::Before),
+        "type" if !value.is_empty() => Ok(Filter::ContentType(value.to_lowercase())),
+        "has" if value.eq_ignore_ascii_case("datum") => Ok(Filter::HasDatum),
+        "hidden" => match value.to_lowercase().as_str() {
+            "true" => Ok(Filter::Hidden(true)),
+            "false" => Ok(Filter::Hidden(false)),
+            _ => Err(format!("invalid hidden:{}, expected true or false", value)),
+        },
+        _ => Err(format!("unknown filter {}:{}", operator, value)),
+    }
+}
+
+/// Parses a `YYYY-MM-DD` date into nanoseconds since the epoch at the start
+/// of the day in UTC.
+fn parse_date(value: &str) -> Result<Timestamp, String> {
+    let invalid = || format!("invalid date {}, expected YYYY-MM-DD", value);
+    let parts = value
+        .split('-')
+        .map(|p| p.parse::<u32>().map_err(|_| invalid()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let (year, month, day) = match parts[..] {
+        [y, m, d]
+            if (1970..=MAX_YEAR).contains(&y)
+                && (1..=12).contains(&m)
+                && d >= 1
+                && d <= days_in_month(y, m) =>
+        {
+            (y, m, d)
+        }
+        _ => return Err(invalid()),
+    };
+    days_from_epoch(year, month, day)
+        .checked_mul(NANOS_PER_DAY)
+        .ok_or_else(invalid)
+}
+
+fn is_leap_year(year: u32) -> bool {
+    year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400))
+}
+
+fn days_in_month(year: u32, month: u32) -> u32 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+fn days_from_epoch(year: u32, month: u32, day: u32) -> u64 {
+    let mut days = (1970..year)
+        .map(|y| if is_leap_year(y) { 366 } else { 365 })
+        .sum::<u64>();
+    days += (1..month)
+        .map(|m| days_in_month(year, m) as u64)
+        .sum::<u64>();
+    days + (day - 1) as u64
+}
+
+/// Whether a content type matches a filter value, ignoring parameters such
+/// as the charset.  A value ending in `/*` matches any subtype.
+fn content_type_matches(content_type: &str, value: &str) -> bool {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_lowercase();
+    match value.strip_suffix("/*") {
+        Some(kind) => essence.split('/').next() == Some(kind),
+        None => essence == value,
+    }
+}
+
+impl Filter {
+    fn matches(&self, record: &Record) -> bool {
+        match self {
+            Filter::Owner(principal) => {
+                record.owner == *principal
+                    || record.attestations.iter().any(|a| a.owner == *principal)
+            }
+            Filter::After(t) => record.priority().created >= *t,
+            Filter::Before(t) => record.priority().created < *t,
+            Filter::ContentType(value) => record
+                .datum
+                .as_ref()
+                .is_some_and(|d| content_type_matches(&d.content_type, value)),
+            Filter::HasDatum => record.datum.is_some(),
+            Filter::Hidden(hidden) => record.hidden == *hidden,
+            Filter::Phrase(phrase) => record.public_attestations().any(|a| {
+                crate::search::tokenize(&a.description)
+                    .join(" ")
+                    .contains(phrase)
+            }),
+            Filter::Word(word) => {
+                record
+                    .digests
+                    .iter()
+                    .any(|d| d.hash.starts_with(word.as_str()))
+                    || record
+                        .public_attestations()
+                        .any(|a| crate::search::tokenize(&a.description).contains(word))
+            }
+        }
+    }
+}
+
+#[test]
+fn check_parse_date() {
+    assert_eq!(parse_date("1970-01-01"), Ok(0));
+    assert_eq!(parse_date("2000-03-01"), Ok(11017 * NANOS_PER_DAY));
+    assert_eq!(parse_date("2026-01-01"), Ok(20454 * NANOS_PER_DAY));
+    assert!(parse_date("2026-02-29").is_err());
+    assert!(parse_date("2024-02-29").is_ok());
+    assert!(parse_date("2026-13-01").is_err());
+    assert!(parse_date("yesterday").is_err());
+    assert!(parse_date("2500-12-31").is_ok());
+    assert!(parse_date("3000-01-01").is_err());
+    assert!(parse_date("4000000000-01-01").is_err());
+}
+
+#[test]
+fn check_content_type_matches() {
+    assert!(content_type_matches("application/pdf", "application/pdf"));
+    assert!(content_type_matches(
+        "Text/HTML; charset=utf-8",
+        "text/html"
+    ));
+    assert!(content_type_matches("image/png", "image/*"));
+    assert!(!content_type_matches("application/pdf", "image/*"));
+}
+
+#[test]
+fn check_parse_query() {
+    let query = Query::parse(
+        r#"quarterly "Board  Minutes" type:application/pdf -hidden:true after:2026-07-01 -draft"#,
+    )
+    .unwrap();
+    assert_eq!(query.terms, vec!["quarterly", "board", "minutes"]);
+    assert_eq!(
+        query.conditions,
+        vec![
+            Condition {
+                negated: false,
+                filter: Filter::Phrase("board minutes".to_string())
+            },
+            Condition {
+                negated: false,
+                filter: Filter::ContentType("application/pdf".to_string())
+            },
+            Condition {
+                negated: true,
+                filter: Filter::Hidden(true)
+            },
+            Condition {
+                negated: false,
+                filter: Filter::After(20635 * NANOS_PER_DAY)
+            },
+            Condition {
+                negated: true,
+                filter: Filter::Word("draft".to_string())
+            },
+        ]
+    );
+    assert_eq!(
+        Query::parse(r#"type:"text/plain""#).unwrap().conditions,
+        vec![Condition {
+            negated: false,
+            filter: Filter::ContentType("text/plain".to_string())
+        }]
+    );
+    assert!(Query::parse(r#""open"#).is_err());
+    assert!(Query::parse("color:red").is_err());
+    assert!(Query::parse("owner:not-a-principal").is_err());
+    assert_eq!(Query::parse("  ").unwrap(), Query::default());
+}
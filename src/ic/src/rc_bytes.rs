@@ -4,11 +4,29 @@ use ic_cdk::export::candid::{
 };
 use serde::de::Deserializer;
 use serde_bytes::ByteBuf;
-use std::ops::Deref;
+use std::ops::{Deref, Range};
 use std::rc::Rc;
 
+/// Shared bytes, or a slice of them.
 #[derive(Clone, Debug)]
-pub struct RcBytes(Rc<ByteBuf>);
+pub struct RcBytes {
+    bytes: Rc<ByteBuf>,
+    range: Range<usize>,
+}
+
+impl RcBytes {
+    /// Returns the bytes in `range` of these, sharing rather than copying
+    /// them.
+    pub fn slice(&self, range: Range<usize>) -> Self {
+        if range.start > range.end || range.end > self.len() {
+            panic!("slice {:?} out of {} bytes", range, self.len());
+        }
+        Self {
+            bytes: self.bytes.clone(),
+            range: self.range.start + range.start..self.range.start + range.end,
+        }
+    }
+}
 
 impl CandidType for RcBytes {
     fn _ty() -> Type {
@@ -19,7 +37,7 @@ impl CandidType for RcBytes {
     where
         S: Serializer,
     {
-        serializer.serialize_blob(&*self.0)
+        serializer.serialize_blob(self)
     }
 }
 
@@ -32,15 +50,34 @@ impl<'de> Deserialize<'de> for RcBytes {
     }
 }
 
+impl Default for RcBytes {
+    fn default() -> Self {
+        Self::from(ByteBuf::new())
+    }
+}
+
 impl From<ByteBuf> for RcBytes {
     fn from(b: ByteBuf) -> Self {
-        Self(Rc::new(b))
+        Self {
+            range: 0..b.len(),
+            bytes: Rc::new(b),
+        }
     }
 }
 
 impl Deref for RcBytes {
     type Target = [u8];
     fn deref(&self) -> &[u8] {
-        &*self.0
+        &self.bytes[self.range.clone()]
     }
 }
+
+#[test]
+fn check_slice() {
+    let bytes = RcBytes::from(ByteBuf::from("a notarized document"));
+    let slice = bytes.slice(2..11);
+    assert_eq!(&*slice, b"notarized");
+    assert_eq!(&*slice.slice(3..6), b"ari");
+    assert!(Rc::ptr_eq(&bytes.bytes, &slice.bytes));
+    assert!(slice.slice(9..9).is_empty());
+}
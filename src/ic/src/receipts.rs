@@ -0,0 +1,94 @@
+use dfnhack7_common::{
+    attestation_leaf_hash, description_hash, record_leaf_hash, Receipt, Record, RecordResult,
+};
+use ic_cdk::api::{data_certificate, trap};
+use ic_certified_map::{fork, labeled, labeled_hash, AsHashTree, Hash, HashTree, RbTree};
+use serde::Serialize;
+use serde_bytes::ByteBuf;
+use std::cell::RefCell;
+
+/// The label of the records tree in the canister's certified data.  It sits
+/// beside the `http_assets` tree of `crate::assets`.
+const RECORDS_LABEL: &[u8] = b"records";
+
+thread_local! {
+    static RECORD_HASHES: RefCell<RecordHashes> = RefCell::new(RbTree::new());
+}
+
+type RecordHashes = RbTree<crate::assets::Key, Hash>;
+
+fn leaf_hash(r: &Record) -> Hash {
+    let attestations = r
+        .attestations
+        .iter()
+        .map(|a| {
+            attestation_leaf_hash(
+                &a.owner,
+                a.created,
+                &description_hash(&a.salt, &a.description),
+                a.hidden,
+            )
+        })
+        .collect::<Vec<_>>();
+    record_leaf_hash(
+        r.algorithm,
+        &r.owner,
+        &r.previous_owners,
+        r.hidden,
+        r.revealed,
+        r.takedown.as_ref().map(|t| t.at),
+        &attestations,
+    )
+}
+
+/// The hash of the labeled records tree, as included in the certified data.
+pub fn labeled_root_hash() -> Hash {
+    RECORD_HASHES.with(|t| labeled_hash(RECORDS_LABEL, &t.borrow().root_hash()))
+}
+
+pub fn certify_record(r: &Record) {
+    RECORD_HASHES.with(|t| {
+        t.borrow_mut().insert(r.hash.clone(), leaf_hash(r));
+    });
+    crate::assets::update_root_hash();
+}
+
+/// Whether the record is in the certification tree, which after an upgrade
+/// it is only once rebuilt.
+pub fn is_certified(key: &str) -> bool {
+    RECORD_HASHES.with(|t| t.borrow().get(key.as_bytes()).is_some())
+}
+
+pub fn do_clear() {
+    RECORD_HASHES.with(|t| {
+        *t.borrow_mut() = RbTree::new();
+    });
+    crate::assets::update_root_hash();
+}
+
+/// Builds a receipt for `record`.  This only works in a query call, where
+/// the data certificate is available.
+pub fn build_receipt(record: RecordResult) -> Receipt {
+    let certificate = data_certificate().unwrap_or_else(|| trap("no data certificate available"));
+    let assets_hash = crate::assets::labeled_root_hash();
+    let witness = RECORD_HASHES.with(|t| {
+        let tree = t.borrow();
+        let witness = fork(
+            HashTree::Pruned(assets_hash),
+            labeled(RECORDS_LABEL, tree.witness(record.hash.as_bytes())),
+        );
+        serialize_tree(&witness)
+    });
+    Receipt {
+        record,
+        certificate: ByteBuf::from(certificate),
+        witness: ByteBuf::from(witness),
+    }
+}
+
+pub fn serialize_tree(tree: &HashTree) -> Vec<u8> {
+    let mut serializer = serde_cbor::ser::Serializer::new(vec![]);
+    serializer.self_describe().unwrap();
+    tree.serialize(&mut serializer).unwrap();
+    serializer.into_inner()
+}
@@ -0,0 +1,223 @@
+use dfnhack7_common::Hash;
+use fuzzy_matcher::skim::SkimMatcherV2;
+use fuzzy_matcher::FuzzyMatcher;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+/// BM25 parameters.
+const K1: f64 = 1.2;
+const B: f64 = 0.75;
+
+/// Matches of a query token as a prefix of an indexed token count this much.
+const PREFIX_WEIGHT: f64 = 0.5;
+/// Matches of a query token found by fuzzy matching the vocabulary (when it
+/// has no exact or prefix match) count this much.
+const FUZZY_WEIGHT: f64 = 0.25;
+/// The maximum number of indexed tokens a single query token expands to.
+const MAX_EXPANSIONS: usize = 50;
+/// The minimum length of a query token to be matched as a prefix or fuzzily.
+const MIN_EXPANSION_LENGTH: usize = 2;
+/// The maximum number of indexed tokens, those sharing the most trigrams with
+/// a query token, which are fuzzy matched against it.
+const MAX_FUZZY_CANDIDATES: usize = 200;
+/// Hexadecimal tokens at least this long are digests, which are found by
+/// prefix and are left out of the trigram index.
+const MIN_DIGEST_LENGTH: usize = 32;
+
+/// An incrementally maintained inverted index of the records.
+#[derive(Default)]
+pub struct SearchIndex {
+    /// For each token, the term frequency in each document containing it.
+    postings: BTreeMap<String, HashMap<Hash, u32>>,
+    /// The tokens of each document, to remove it again.
+    documents: HashMap<Hash, Vec<String>>,
+    /// For each trigram, the indexed tokens containing it, to find the
+    /// candidates for fuzzy matching.
+    trigrams: HashMap<String, BTreeSet<String>>,
+    total_length: u64,
+}
+
+/// Splits text into lowercase alphanumeric tokens.
+pub fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|t| !t.is_empty())
+        .map(|t| t.to_lowercase())
+        .collect()
+}
+
+/// The distinct trigrams of a token padded with a space on either side, or
+/// none for a digest.
+fn trigrams(token: &str) -> BTreeSet<String> {
+    if token.len() >= MIN_DIGEST_LENGTH && token.chars().all(|c| c.is_ascii_hexdigit()) {
+        return BTreeSet::new();
+    }
+    let chars = std::iter::once(' ')
+        .chain(token.chars())
+        .chain(std::iter::once(' '))
+        .collect::<Vec<_>>();
+    chars.windows(3).map(|w| w.iter().collect()).collect()
+}
+
+impl SearchIndex {
+    /// Indexes the document `key` with the given fields, replacing any
+    /// previous version of it.
+    pub fn insert<'a, I: IntoIterator<Item = &'a str>>(&mut self, key: &str, fields: I) {
+        self.remove(key);
+        let tokens = fields.into_iter().flat_map(tokenize).collect::<Vec<_>>();
+        for token in tokens.iter() {
+            let postings = self.postings.entry(token.clone()).or_default();
+            if postings.is_empty() {
+                for trigram in trigrams(token) {
+                    self.trigrams
+                        .entry(trigram)
+                        .or_default()
+                        .insert(token.clone());
+                }
+            }
+            *postings.entry(key.to_string()).or_default() += 1;
+        }
+        self.total_length += tokens.len() as u64;
+        self.documents.insert(key.to_string(), tokens);
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        if let Some(tokens) = self.documents.remove(key) {
+            for token in tokens.iter() {
+                if let Some(postings) = self.postings.get_mut(token) {
+                    postings.remove(key);
+                    if postings.is_empty() {
+                        self.postings.remove(token);
+                        for trigram in trigrams(token) {
+                            if let Some(tokens) = self.trigrams.get_mut(&trigram) {
+                                tokens.remove(token);
+                                if tokens.is_empty() {
+                                    self.trigrams.remove(&trigram);
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            self.total_length -= tokens.len() as u64;
+        }
+    }
+
+    pub fn clear(&mut self) {
+        *self = SearchIndex::default();
+    }
+
+    /// The indexed tokens matching a query token, with their weights.
+    fn expand(&self, token: &str, matcher: &SkimMatcherV2) -> Vec<(&str, f64)> {
+        let mut expansions = vec![];
+        if let Some((t, _)) = self.postings.get_key_value(token) {
+            expansions.push((&t[..], 1.0));
+        }
+        if token.len() < MIN_EXPANSION_LENGTH {
+            return expansions;
+        }
+        for (t, _) in self
+            .postings
+            .range::<str, _>((std::ops::Bound::Excluded(token), std::ops::Bound::Unbounded))
+            .take_while(|(t, _)| t.starts_with(token))
+            .take(MAX_EXPANSIONS)
+        {
+            expansions.push((&t[..], PREFIX_WEIGHT));
+        }
+        if expansions.is_empty() {
+            let mut shared: HashMap<&str, usize> = HashMap::new();
+            for trigram in trigrams(token) {
+                for t in self.trigrams.get(&trigram).into_iter().flatten() {
+                    *shared.entry(t).or_default() += 1;
+                }
+            }
+            let mut candidates = shared.into_iter().collect::<Vec<_>>();
+            candidates.sort_by_key(|(t, count)| (std::cmp::Reverse(*count), *t));
+            let mut fuzzy = candidates
+                .into_iter()
+                .take(MAX_FUZZY_CANDIDATES)
+                .filter_map(|(t, _)| matcher.fuzzy_match(t, token).map(|score| (score, t)))
+                .collect::<Vec<_>>();
+            fuzzy.sort_by_key(|(score, _)| std::cmp::Reverse(*score));
+            for (_, t) in fuzzy.into_iter().take(MAX_EXPANSIONS) {
+                expansions.push((t, FUZZY_WEIGHT));
+            }
+        }
+        expansions
+    }
+
+    /// Scores the documents matching any of the query tokens with BM25.
+    /// Only those documents are returned, they are the candidates for
+    /// further ranking.
+    pub fn score(&self, query: &[String], matcher: &SkimMatcherV2) -> HashMap<Hash, f64> {
+        let mut scores: HashMap<Hash, f64> = HashMap::new();
+        if self.documents.is_empty() {
+            return scores;
+        }
+        let n = self.documents.len() as f64;
+        let average_length = self.total_length as f64 / n;
+        for token in query {
+            for (t, weight) in self.expand(token, matcher) {
+                let postings = &self.postings[t];
+                let df = postings.len() as f64;
+                let idf = (1.0 + (n - df + 0.5) / (df + 0.5)).ln();
+                for (key, tf) in postings.iter() {
+                    let tf = *tf as f64;
+                    let length = self.documents[key].len() as f64;
+                    let norm = K1 * (1.0 - B + B * length / average_length.max(1.0));
+                    *scores.entry(key.clone()).or_default() +=
+                        weight * idf * tf * (K1 + 1.0) / (tf + norm);
+                }
+            }
+        }
+        scores
+    }
+}
+
+#[test]
+fn check_tokenize() {
+    assert_eq!(
+        tokenize("Hello, World-2021!"),
+        vec!["hello", "world", "2021"]
+    );
+    assert!(tokenize(" -- ").is_empty());
+}
+
+#[test]
+fn check_search_index() {
+    let matcher = SkimMatcherV2::default();
+    let mut index = SearchIndex::default();
+    index.insert("a", vec!["abc123", "patent draft for the engine"]);
+    index.insert("b", vec!["def456", "engine engine engine manual"]);
+    index.insert("c", vec!["0a1b2c", "holiday photos"]);
+
+    let scores = index.score(&tokenize("engine"), &matcher);
+    assert_eq!(scores.len(), 2);
+    assert!(scores["b"] > scores["a"]);
+
+    // Prefixes, e.g. of hashes, match.
+    let scores = index.score(&tokenize("def4"), &matcher);
+    assert_eq!(scores.keys().collect::<Vec<_>>(), vec!["b"]);
+
+    // Typos fall back to fuzzy matching of the vocabulary.
+    let scores = index.score(&tokenize("holdy"), &matcher);
+    assert!(scores.contains_key("c"));
+    // Digests aren't candidates for fuzzy matching.
+    let digest = "ab".repeat(32);
+    index.insert("d", vec![&digest[..]]);
+    assert!(index
+        .trigrams
+        .values()
+        .all(|tokens| !tokens.contains(&digest)));
+    index.remove("d");
+
+    // Reindexing replaces the document.
+    index.insert("a", vec!["abc123", "holiday plans"]);
+    assert!(!index.score(&tokenize("engine"), &matcher).contains_key("a"));
+    index.remove("b");
+    assert!(index.score(&tokenize("engine"), &matcher).is_empty());
+    assert_eq!(index.total_length, 6);
+    assert!(index
+        .trigrams
+        .values()
+        .all(|tokens| !tokens.contains("manual")));
+    assert!(index.trigrams["hol"].contains("holiday"));
+}
@@ -0,0 +1,439 @@
+//! A region of stable memory holding blobs, the datum and asset chunks and
+//! the nodes of the maps of `crate::btree`, so that they survive upgrades
+//! without being serialized and aren't limited by the wasm heap.  Only the
+//! remaining metadata is saved on upgrade, after the last allocated blob.
+//!
+//! The region starts with a header:
+//!
+//! | offset | field                                       |
+//! |--------|---------------------------------------------|
+//! | 0      | magic `DFNB`                                |
+//! | 4      | layout version, u32                         |
+//! | 8      | end of the allocated blocks, u64            |
+//! | 16     | offset of the saved metadata, u64           |
+//! | 24     | length of the saved metadata, u64           |
+//! | 32     | free list heads, a u64 per size class       |
+//! | 512    | map headers, the root and length, two u64s  |
+//! |        | per `crate::btree::MapId`                   |
+//!
+//! Blocks are allocated in power of two size classes, from 64 bytes to
+//! `MAX_BLOB_SIZE`.  A free block is zeroed, as content taken down must not
+//! linger, except for the offset of the next free block of its class in its
+//! first 8 bytes.  All integers are little endian.
+
+use ic_cdk::export::candid::{CandidType, Deserialize};
+use std::cell::RefCell;
+
+pub const WASM_PAGE_SIZE: u64 = 64 * 1024;
+/// The largest blob, callers split larger content.
+pub const MAX_BLOB_SIZE: usize = 1 << MAX_CLASS_BITS;
+
+const MAGIC: &[u8; 4] = b"DFNB";
+/// `ic_cdk::storage::stable_save` writes a Candid message, which starts
+/// with this magic.
+const LEGACY_MAGIC: &[u8; 4] = b"DIDL";
+const LAYOUT_VERSION: u32 = 1;
+const MIN_CLASS_BITS: u32 = 6;
+const MAX_CLASS_BITS: u32 = 20;
+const CLASSES: usize = (MAX_CLASS_BITS - MIN_CLASS_BITS + 1) as usize;
+const TOP_OFFSET: u64 = 8;
+const METADATA_OFFSET: u64 = 16;
+const FREE_LISTS_OFFSET: u64 = 32;
+const MAPS_OFFSET: u64 = 512;
+/// The number of map headers the header has room for.
+pub const MAX_MAPS: usize = 32;
+const DATA_START: u64 = 4096;
+
+/// Byte addressed memory which grows in wasm pages.
+pub trait Memory {
+    /// The size in pages.
+    fn size(&self) -> u64;
+    /// Grows by `pages`, returning false if it can't.
+    fn grow(&self, pages: u64) -> bool;
+    fn read(&self, offset: u64, buf: &mut [u8]);
+    fn write(&self, offset: u64, buf: &[u8]);
+}
+
+/// The 64-bit stable memory API, which the CDK doesn't wrap yet.  The 32-bit
+/// one only addresses the first 4 GiB.
+#[cfg(all(not(test), target_arch = "wasm32"))]
+mod ic0 {
+    #[link(wasm_import_module = "ic0")]
+    extern "C" {
+        pub fn stable64_size() -> u64;
+        pub fn stable64_grow(additional_pages: u64) -> i64;
+        pub fn stable64_read(dst: u64, offset: u64, size: u64);
+        pub fn stable64_write(offset: u64, src: u64, size: u64);
+    }
+}
+
+#[cfg(all(not(test), not(target_arch = "wasm32")))]
+mod ic0 {
+    pub unsafe fn stable64_size() -> u64 {
+        panic!("stable64_size should only be called inside canisters.");
+    }
+
+    pub unsafe fn stable64_grow(_additional_pages: u64) -> i64 {
+        panic!("stable64_grow should only be called inside canisters.");
+    }
+
+    pub unsafe fn stable64_read(_dst: u64, _offset: u64, _size: u64) {
+        panic!("stable64_read should only be called inside canisters.");
+    }
+
+    pub unsafe fn stable64_write(_offset: u64, _src: u64, _size: u64) {
+        panic!("stable64_write should only be called inside canisters.");
+    }
+}
+
+/// The canister's stable memory, through the 64-bit API.
+#[cfg(not(test))]
+#[derive(Default)]
+pub struct IcMemory;
+
+#[cfg(not(test))]
+impl Memory for IcMemory {
+    fn size(&self) -> u64 {
+        unsafe { ic0::stable64_size() }
+    }
+
+    fn grow(&self, pages: u64) -> bool {
+        unsafe { ic0::stable64_grow(pages) >= 0 }
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) {
+        unsafe { ic0::stable64_read(buf.as_mut_ptr() as u64, offset, buf.len() as u64) }
+    }
+
+    fn write(&self, offset: u64, buf: &[u8]) {
+        unsafe { ic0::stable64_write(offset, buf.as_ptr() as u64, buf.len() as u64) }
+    }
+}
+
+#[cfg(test)]
+#[derive(Default)]
+pub struct VecMemory(RefCell<Vec<u8>>);
+
+#[cfg(test)]
+impl Memory for VecMemory {
+    fn size(&self) -> u64 {
+        self.0.borrow().len() as u64 / WASM_PAGE_SIZE
+    }
+
+    fn grow(&self, pages: u64) -> bool {
+        let mut bytes = self.0.borrow_mut();
+        let length = bytes.len() + (pages * WASM_PAGE_SIZE) as usize;
+        bytes.resize(length, 0);
+        true
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) {
+        let offset = offset as usize;
+        buf.copy_from_slice(&self.0.borrow()[offset..offset + buf.len()]);
+    }
+
+    fn write(&self, offset: u64, buf: &[u8]) {
+        let offset = offset as usize;
+        self.0.borrow_mut()[offset..offset + buf.len()].copy_from_slice(buf);
+    }
+}
+
+/// A reference to a blob in the region.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct Blob {
+    pub offset: u64,
+    pub length: u32,
+}
+
+pub struct BlobStore<M: Memory> {
+    memory: M,
+    top: u64,
+    free_lists: [u64; CLASSES],
+}
+
+fn class_of(length: usize) -> usize {
+    if length > MAX_BLOB_SIZE {
+        ic_cdk::trap(&format!("blob of {} bytes is too large", length));
+    }
+    let bits = (usize::BITS - length.saturating_sub(1).leading_zeros()).max(MIN_CLASS_BITS);
+    (bits - MIN_CLASS_BITS) as usize
+}
+
+fn class_size(class: usize) -> u64 {
+    1 << (class as u32 + MIN_CLASS_BITS)
+}
+
+fn read_u64<M: Memory>(memory: &M, offset: u64) -> u64 {
+    let mut bytes = [0; 8];
+    memory.read(offset, &mut bytes);
+    u64::from_le_bytes(bytes)
+}
+
+fn write_u64<M: Memory>(memory: &M, offset: u64, value: u64) {
+    memory.write(offset, &value.to_le_bytes());
+}
+
+/// Whether the memory holds a state saved with `stable_save`, from before
+/// the blob region.
+pub fn is_legacy<M: Memory>(memory: &M) -> bool {
+    memory.size() > 0 && {
+        let mut magic = [0; 4];
+        memory.read(0, &mut magic);
+        &magic == LEGACY_MAGIC
+    }
+}
+
+impl<M: Memory> BlobStore<M> {
+    /// Creates an empty region, overwriting whatever the memory held.
+    pub fn init(memory: M) -> Self {
+        let store = BlobStore {
+            memory,
+            top: DATA_START,
+            free_lists: [0; CLASSES],
+        };
+        store.ensure_capacity(DATA_START);
+        store.memory.write(0, MAGIC);
+        store.memory.write(4, &LAYOUT_VERSION.to_le_bytes());
+        write_u64(&store.memory, TOP_OFFSET, store.top);
+        write_u64(&store.memory, METADATA_OFFSET, 0);
+        write_u64(&store.memory, METADATA_OFFSET + 8, 0);
+        for class in 0..CLASSES {
+            write_u64(&store.memory, FREE_LISTS_OFFSET + 8 * class as u64, 0);
+        }
+        // A zeroed header is an empty map.
+        store.memory.write(MAPS_OFFSET, &[0; 16 * MAX_MAPS]);
+        store
+    }
+
+    /// Opens the region of the memory, if it has one.
+    pub fn load(memory: M) -> Option<Self> {
+        if memory.size() == 0 {
+            return None;
+        }
+        let mut header = [0; 8];
+        memory.read(0, &mut header);
+        if &header[..4] != MAGIC {
+            return None;
+        }
+        let version = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        if version != LAYOUT_VERSION {
+            ic_cdk::trap(&format!("unsupported stable memory layout {}", version));
+        }
+        let mut free_lists = [0; CLASSES];
+        for (class, head) in free_lists.iter_mut().enumerate() {
+            *head = read_u64(&memory, FREE_LISTS_OFFSET + 8 * class as u64);
+        }
+        Some(BlobStore {
+            top: read_u64(&memory, TOP_OFFSET),
+            memory,
+            free_lists,
+        })
+    }
+
+    fn ensure_capacity(&self, end: u64) {
+        let pages = end.div_ceil(WASM_PAGE_SIZE);
+        let size = self.memory.size();
+        if pages > size && !self.memory.grow(pages - size) {
+            ic_cdk::trap("out of stable memory");
+        }
+    }
+
+    fn set_free_list(&mut self, class: usize, head: u64) {
+        self.free_lists[class] = head;
+        write_u64(&self.memory, FREE_LISTS_OFFSET + 8 * class as u64, head);
+    }
+
+    pub fn insert(&mut self, bytes: &[u8]) -> Blob {
+        let class = class_of(bytes.len());
+        let offset = match self.free_lists[class] {
+            0 => {
+                let offset = self.top;
+                self.top += class_size(class);
+                self.ensure_capacity(self.top);
+                write_u64(&self.memory, TOP_OFFSET, self.top);
+                offset
+            }
+            head => {
+                let next = read_u64(&self.memory, head);
+                self.set_free_list(class, next);
+                head
+            }
+        };
+        self.memory.write(offset, bytes);
+        Blob {
+            offset,
+            length: bytes.len() as u32,
+        }
+    }
+
+    pub fn get(&self, blob: Blob) -> Vec<u8> {
+        let mut bytes = vec![0; blob.length as usize];
+        self.memory.read(blob.offset, &mut bytes);
+        bytes
+    }
+
+    /// Overwrites the blob with `bytes`, which must not be longer.
+    pub fn update(&mut self, blob: Blob, bytes: &[u8]) {
+        assert!(bytes.len() <= blob.length as usize, "blob overflow");
+        self.memory.write(blob.offset, bytes);
+    }
+
+    pub fn remove(&mut self, blob: Blob) {
+        let class = class_of(blob.length as usize);
+        self.memory
+            .write(blob.offset, &vec![0; blob.length as usize]);
+        write_u64(&self.memory, blob.offset, self.free_lists[class]);
+        self.set_free_list(class, blob.offset);
+    }
+
+    /// Saves the metadata after the allocated blocks, where it stays until
+    /// the next allocation.
+    pub fn save_metadata(&mut self, bytes: &[u8]) {
+        self.ensure_capacity(self.top + bytes.len() as u64);
+        self.memory.write(self.top, bytes);
+        write_u64(&self.memory, METADATA_OFFSET, self.top);
+        write_u64(&self.memory, METADATA_OFFSET + 8, bytes.len() as u64);
+    }
+
+    /// The root and the length of the map `map`.
+    pub fn map_header(&self, map: usize) -> (u64, u64) {
+        assert!(map < MAX_MAPS);
+        let offset = MAPS_OFFSET + 16 * map as u64;
+        (
+            read_u64(&self.memory, offset),
+            read_u64(&self.memory, offset + 8),
+        )
+    }
+
+    pub fn set_map_header(&mut self, map: usize, root: u64, length: u64) {
+        assert!(map < MAX_MAPS);
+        let offset = MAPS_OFFSET + 16 * map as u64;
+        write_u64(&self.memory, offset, root);
+        write_u64(&self.memory, offset + 8, length);
+    }
+
+    pub fn metadata(&self) -> Vec<u8> {
+        let offset = read_u64(&self.memory, METADATA_OFFSET);
+        let mut bytes = vec![0; read_u64(&self.memory, METADATA_OFFSET + 8) as usize];
+        self.memory.read(offset, &mut bytes);
+        bytes
+    }
+}
+
+/// The memory of the region: the canister's stable memory, or in tests a
+/// vector.
+#[cfg(not(test))]
+type CanisterMemory = IcMemory;
+#[cfg(test)]
+type CanisterMemory = VecMemory;
+
+thread_local! {
+    static BLOBS: RefCell<Option<BlobStore<CanisterMemory>>> = const { RefCell::new(None) };
+}
+
+fn with_blobs<R>(f: impl FnOnce(&mut BlobStore<CanisterMemory>) -> R) -> R {
+    BLOBS.with(|b| {
+        f(b.borrow_mut()
+            .as_mut()
+            .unwrap_or_else(|| ic_cdk::trap("stable memory not initialized")))
+    })
+}
+
+/// Creates an empty region in the canister's stable memory.
+pub fn init() {
+    BLOBS.with(|b| b.replace(Some(BlobStore::init(CanisterMemory::default()))));
+}
+
+/// Opens the region of the canister's stable memory, returning the saved
+/// metadata, or `None` if there is no region.
+pub fn load() -> Option<Vec<u8>> {
+    let store = BlobStore::load(CanisterMemory::default())?;
+    let metadata = store.metadata();
+    BLOBS.with(|b| b.replace(Some(store)));
+    Some(metadata)
+}
+
+pub fn is_legacy_state() -> bool {
+    is_legacy(&CanisterMemory::default())
+}
+
+pub fn insert(bytes: &[u8]) -> Blob {
+    with_blobs(|b| b.insert(bytes))
+}
+
+pub fn get(blob: Blob) -> Vec<u8> {
+    with_blobs(|b| b.get(blob))
+}
+
+pub fn update(blob: Blob, bytes: &[u8]) {
+    with_blobs(|b| b.update(blob, bytes))
+}
+
+pub fn remove(blob: Blob) {
+    with_blobs(|b| b.remove(blob))
+}
+
+pub fn map_header(map: usize) -> (u64, u64) {
+    with_blobs(|b| b.map_header(map))
+}
+
+pub fn set_map_header(map: usize, root: u64, length: u64) {
+    with_blobs(|b| b.set_map_header(map, root, length))
+}
+
+pub fn save_metadata(bytes: &[u8]) {
+    with_blobs(|b| b.save_metadata(bytes))
+}
+
+#[test]
+fn check_class_of() {
+    assert_eq!(class_of(0), 0);
+    assert_eq!(class_of(64), 0);
+    assert_eq!(class_of(65), 1);
+    assert_eq!(class_of(MAX_BLOB_SIZE), CLASSES - 1);
+    assert_eq!(class_size(CLASSES - 1), MAX_BLOB_SIZE as u64);
+}
+
+#[test]
+fn check_blob_store() {
+    let mut store = BlobStore::init(VecMemory::default());
+    let a = store.insert(b"hello");
+    let b = store.insert(&[7; 1000]);
+    assert_eq!(store.get(a), b"hello");
+    assert_eq!(store.get(b), vec![7; 1000]);
+    // A freed block is reused by its size class only.
+    store.remove(a);
+    let c = store.insert(&[8; 100]);
+    assert_ne!(c.offset, a.offset);
+    let d = store.insert(b"world");
+    assert_eq!(d.offset, a.offset);
+    // A freed block keeps nothing of its blob.
+    store.remove(b);
+    let mut freed = vec![1; 992];
+    store.memory.read(b.offset + 8, &mut freed);
+    assert_eq!(freed, vec![0; 992]);
+    let b = store.insert(&[7; 1000]);
+    store.update(c, &[9; 100]);
+    store.set_map_header(1, b.offset, 3);
+    store.save_metadata(b"metadata");
+
+    let store = BlobStore::load(store.memory).unwrap();
+    assert_eq!(store.metadata(), b"metadata");
+    assert_eq!(store.get(b), vec![7; 1000]);
+    assert_eq!(store.get(c), vec![9; 100]);
+    assert_eq!(store.map_header(0), (0, 0));
+    assert_eq!(store.map_header(1), (b.offset, 3));
+    assert_eq!(store.get(d), b"world");
+    assert!(!is_legacy(&store.memory));
+}
+
+#[test]
+fn check_legacy_detection() {
+    let memory = VecMemory::default();
+    assert!(!is_legacy(&memory));
+    assert!(BlobStore::load(VecMemory::default()).is_none());
+    memory.grow(1);
+    memory.write(0, b"DIDL\x01\x02");
+    assert!(is_legacy(&memory));
+    assert!(BlobStore::load(memory).is_none());
+}
@@ -0,0 +1,356 @@
+//! The state saved across upgrades.  The records, the assets and everything
+//! else which grows with use are in maps in the blob region, see
+//! `crate::btree`, which stay as they are.  Only the rest is saved, in an
+//! envelope giving the version of its format:
+//!
+//! * 0: the state of the first release, content included, saved with
+//!   `stable_save` before the blob region,
+//! * 1: `StableState`, saved in the blob region.
+//!
+//! To change a saved type, copy the saved types of the current version as
+//! `StableStateV<n>`, bump `STATE_VERSION`, decode the old version in
+//! `decode_state` and convert it in `migrate`, which also converts the
+//! values of the maps whose type changed, e.g. for a field of `Record`.
+//! Optional fields can be added without a new version, they decode as
+//! `None` from older states and values.  A state newer than the code is
+//! rejected, which fails the upgrade and keeps the canister as it was.
+
+use crate::Snapshot;
+use candid::{CandidType, Deserialize, Principal};
+use dfnhack7_common::*;
+use serde_bytes::ByteBuf;
+use std::collections::HashMap;
+
+const STATE_MAGIC: &[u8; 4] = b"DFNV";
+const STATE_VERSION: u32 = 1;
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub(crate) struct StableState {
+    pub(crate) secret: ByteBuf,
+    pub(crate) assets: crate::assets::StableState,
+    pub(crate) pending_clear: Option<PendingClear>,
+    pub(crate) snapshot: Option<Snapshot>,
+}
+
+/// The state of the first release, saved with `stable_save`.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct StableStateV0 {
+    data: HashMap<Hash, RecordV0>,
+    assets: crate::assets::StableStateV0,
+}
+
+/// A record before attestations, digests and everything since.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct RecordV0 {
+    hash: Hash,
+    owner: Principal,
+    datum: Option<Datum>,
+    description: String,
+    hidden: bool,
+    created: Timestamp,
+}
+
+impl RecordV0 {
+    /// The datum's content, the same as that of its asset, is only used to
+    /// compute the other digests.
+    fn migrate(self) -> Record {
+        let digests = match &self.datum {
+            Some(datum) => crate::DATUM_ALGORITHMS
+                .iter()
+                .map(|algorithm| TaggedHash {
+                    algorithm: *algorithm,
+                    hash: match algorithm {
+                        HashAlgorithm::Sha256 => self.hash.clone(),
+                        _ => hex::encode(algorithm.digest(&datum.content)),
+                    },
+                })
+                .collect(),
+            None => vec![TaggedHash {
+                algorithm: HashAlgorithm::Sha256,
+                hash: self.hash.clone(),
+            }],
+        };
+        Record {
+            hash: self.hash,
+            algorithm: HashAlgorithm::Sha256,
+            digests,
+            owner: self.owner,
+            datum: self.datum.map(|datum| DatumInfo {
+                content_type: datum.content_type,
+            }),
+            hidden: self.hidden,
+            attestations: vec![Attestation {
+                owner: self.owner,
+                created: self.created,
+                description: self.description,
+                hidden: self.hidden,
+                // The first release had no hidden descriptions to protect.
+                salt: ByteBuf::new(),
+            }],
+            grants: vec![],
+            reveal_at: None,
+            revealed: None,
+            link_epoch: 0,
+            previous_owners: vec![],
+            transfer_to: None,
+            takedown: None,
+        }
+    }
+}
+
+impl StableStateV0 {
+    /// Moves the records and the assets to the maps in the blob region.
+    fn migrate(self) -> StableState {
+        for (key, record) in self.data.into_iter() {
+            let record = record.migrate();
+            crate::insert_digests(&record);
+            crate::RECORDS.insert(&key, &record);
+        }
+        StableState {
+            secret: ByteBuf::new(),
+            assets: self.assets.migrate(),
+            pending_clear: None,
+            snapshot: None,
+        }
+    }
+}
+
+enum VersionedState {
+    V0(StableStateV0),
+    V1(Box<StableState>),
+}
+
+fn encode_state(state: &StableState) -> Vec<u8> {
+    let mut bytes = STATE_MAGIC.to_vec();
+    bytes.extend_from_slice(&STATE_VERSION.to_le_bytes());
+    bytes.extend(candid::encode_one(state).expect("failed to encode stable state"));
+    bytes
+}
+
+/// Decodes the metadata saved in the blob region.
+fn decode_state(bytes: &[u8]) -> Result<VersionedState, String> {
+    let invalid = |e: candid::Error| format!("invalid stable state: {}", e);
+    if bytes.len() < 8 || !bytes.starts_with(STATE_MAGIC) {
+        return Err("no stable state".to_string());
+    }
+    let version = u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+    match version {
+        1 => candid::decode_one(&bytes[8..])
+            .map(|state| VersionedState::V1(Box::new(state)))
+            .map_err(invalid),
+        v if v > STATE_VERSION => Err(format!(
+            "can't downgrade the stable state from version {} to {}",
+            v, STATE_VERSION
+        )),
+        v => Err(format!("unknown stable state version {}", v)),
+    }
+}
+
+/// Decodes the state saved with `stable_save`, which is followed by the
+/// rest of the stable memory.
+fn decode_state_v0(bytes: &[u8]) -> Result<VersionedState, String> {
+    let invalid = |e: candid::Error| format!("invalid stable state: {}", e);
+    let mut de = candid::de::IDLDeserialize::new(bytes).map_err(invalid)?;
+    de.get_value().map(VersionedState::V0).map_err(invalid)
+}
+
+/// Converts a state to the current version, one version at a time.
+fn migrate(mut state: VersionedState) -> StableState {
+    loop {
+        state = match state {
+            VersionedState::V0(state) => VersionedState::V1(Box::new(state.migrate())),
+            VersionedState::V1(state) => return *state,
+        }
+    }
+}
+
+pub fn save(state: &StableState) {
+    crate::stable::save_metadata(&encode_state(state));
+}
+
+/// Restores the saved state, in the current version.  Traps if it can't,
+/// which fails the upgrade.
+pub fn restore() -> StableState {
+    let state = if crate::stable::is_legacy_state() {
+        let state = decode_state_v0(&ic_cdk::api::stable::stable_bytes());
+        // The state is in the heap now, the blob region can overwrite it.
+        crate::stable::init();
+        state
+    } else {
+        match crate::stable::load() {
+            Some(bytes) => decode_state(&bytes),
+            None => Err("no stable state".to_string()),
+        }
+    };
+    migrate(state.unwrap_or_else(|e| ic_cdk::trap(&e)))
+}
+
+#[cfg(test)]
+fn empty_state() -> StableState {
+    StableState {
+        secret: ByteBuf::from(vec![1, 2, 3]),
+        assets: crate::assets::StableState::default(),
+        pending_clear: None,
+        snapshot: None,
+    }
+}
+
+#[test]
+fn check_state_versions() {
+    let state = empty_state();
+    match decode_state(&encode_state(&state)) {
+        Ok(VersionedState::V1(decoded)) => assert_eq!(decoded.secret, state.secret),
+        _ => panic!("expected version 1"),
+    }
+    // Without the envelope.
+    assert!(decode_state(&candid::encode_one(&state).unwrap()).is_err());
+    // Saved by a newer release.
+    let mut newer = encode_state(&state);
+    newer[4..8].copy_from_slice(&(STATE_VERSION + 1).to_le_bytes());
+    assert!(decode_state(&newer).err().unwrap().contains("downgrade"));
+    assert!(decode_state(b"DFNV").is_err());
+    assert!(decode_state(&[0; 16]).is_err());
+}
+
+/// The types of the first release, as it saved its state with
+/// `stable_save((stable_state,))`.
+#[cfg(test)]
+#[derive(CandidType)]
+struct SavedStateV0 {
+    data: HashMap<Hash, SavedRecordV0>,
+    assets: SavedAssetsV0,
+}
+
+#[cfg(test)]
+#[derive(CandidType)]
+struct SavedRecordV0 {
+    hash: Hash,
+    owner: Principal,
+    datum: Option<Datum>,
+    description: String,
+    hidden: bool,
+    created: Timestamp,
+}
+
+#[cfg(test)]
+#[derive(CandidType)]
+struct SavedAssetsV0 {
+    authorized: Vec<Principal>,
+    stable_assets: HashMap<String, SavedAssetV0>,
+}
+
+#[cfg(test)]
+#[derive(CandidType)]
+struct SavedAssetV0 {
+    content_type: String,
+    encodings: HashMap<String, SavedAssetEncodingV0>,
+}
+
+#[cfg(test)]
+#[derive(CandidType)]
+struct SavedAssetEncodingV0 {
+    modified: Timestamp,
+    content_chunks: Vec<ByteBuf>,
+    total_length: usize,
+    certified: bool,
+    sha256: [u8; 32],
+}
+
+#[test]
+fn check_state_v0() {
+    let owner = Principal::from_slice(&[1]);
+    let content = ByteBuf::from("hello");
+    let datum_hash = hex::encode(HashAlgorithm::Sha256.digest(&content));
+    let bare_hash = "ab".repeat(32);
+    let mut encodings = HashMap::new();
+    encodings.insert(
+        "identity".to_string(),
+        SavedAssetEncodingV0 {
+            modified: 1,
+            content_chunks: vec![content.clone()],
+            total_length: content.len(),
+            certified: true,
+            sha256: [0; 32],
+        },
+    );
+    let mut stable_assets = HashMap::new();
+    stable_assets.insert(
+        format!("/{}", datum_hash),
+        SavedAssetV0 {
+            content_type: "text/plain".to_string(),
+            encodings,
+        },
+    );
+    let mut data = HashMap::new();
+    data.insert(
+        datum_hash.clone(),
+        SavedRecordV0 {
+            hash: datum_hash.clone(),
+            owner,
+            datum: Some(Datum {
+                content_type: "text/plain".to_string(),
+                content,
+            }),
+            description: "minutes".to_string(),
+            hidden: true,
+            created: 1,
+        },
+    );
+    data.insert(
+        bare_hash.clone(),
+        SavedRecordV0 {
+            hash: bare_hash.clone(),
+            owner,
+            datum: None,
+            description: "contract".to_string(),
+            hidden: false,
+            created: 2,
+        },
+    );
+    let saved = SavedStateV0 {
+        data,
+        assets: SavedAssetsV0 {
+            authorized: vec![owner],
+            stable_assets,
+        },
+    };
+    let mut bytes = candid::encode_args((saved,)).unwrap();
+    // The rest of the stable memory page.
+    bytes.resize(bytes.len() + 100, 0);
+    let state = match decode_state_v0(&bytes) {
+        Ok(VersionedState::V0(state)) => state,
+        _ => panic!("expected version 0"),
+    };
+    assert_eq!(state.data.len(), 2);
+
+    let record = state.data[&datum_hash].clone().migrate();
+    assert_eq!(record.hash, datum_hash);
+    assert_eq!(record.algorithm, HashAlgorithm::Sha256);
+    assert_eq!(record.digests.len(), crate::DATUM_ALGORITHMS.len());
+    assert_eq!(
+        record.digests[1].hash,
+        hex::encode(record.digests[1].algorithm.digest(b"hello"))
+    );
+    assert_eq!(record.datum.unwrap().content_type, "text/plain");
+    assert!(record.hidden);
+    assert_eq!(record.attestations.len(), 1);
+    let priority = &record.attestations[0];
+    assert_eq!(priority.owner, owner);
+    assert_eq!(priority.created, 1);
+    assert_eq!(priority.description, "minutes");
+    assert!(priority.hidden);
+
+    let record = state.data[&bare_hash].clone().migrate();
+    assert_eq!(record.digests.len(), 1);
+    assert_eq!(record.digests[0].hash, bare_hash);
+    assert!(record.datum.is_none());
+    assert_eq!(record.priority().created, 2);
+    assert_eq!(record.priority().description, "contract");
+
+    crate::stable::init();
+    let assets = state.assets.migrate();
+    assert_eq!(assets.role(&owner), Some(Role::Admin));
+    let key = format!("/{}", datum_hash);
+    assert_eq!(crate::assets::content(&key, "identity").unwrap(), b"hello");
+    assert!(crate::assets::content(&key, "gzip").is_none());
+}
@@ -0,0 +1,644 @@
+//! Offline verification of notarization receipts.
+//!
+//! A receipt (see `dfnhack7_common::Receipt`) carries the IC certificate for
+//! the notary canister's certified data and a witness for the record's leaf.
+//! Checking it needs only the IC root key, not the canister or our frontend.
+
+use dfnhack7_common::{
+    attestation_leaf_hash, description_hash, record_leaf_hash, Receipt, Timestamp,
+};
+use serde_cbor::Value;
+use sha2::Digest;
+use std::collections::BTreeMap;
+use std::fmt;
+
+pub const IC_ROOT_KEY: &[u8; 133] = b"\x30\x81\x82\x30\x1d\x06\x0d\x2b\x06\x01\x04\x01\x82\xdc\x7c\x05\x03\x01\x02\x01\x06\x0c\x2b\x06\x01\x04\x01\x82\xdc\x7c\x05\x03\x02\x01\x03\x61\x00\x81\x4c\x0e\x6e\xc7\x1f\xab\x58\x3b\x08\xbd\x81\x37\x3c\x25\x5c\x3c\x37\x1b\x2e\x84\x86\x3c\x98\xa4\xf1\xe0\x8b\x74\x23\x5d\x14\xfb\x5d\x9c\x0c\xd5\x46\xd9\x68\x5f\x91\x3a\x0c\x0b\x2c\xc5\x34\x15\x83\xbf\x4b\x43\x92\xe4\x67\xdb\x96\xd6\x5b\x9b\xb4\xcb\x71\x71\x12\xf8\x47\x2e\x0d\x5a\x4d\x14\x50\x5f\xfd\x74\x84\xb0\x12\x91\x09\x1c\x5f\x87\xb9\x88\x83\x46\x3f\x98\x09\x1a\x0b\xaa\xae";
+
+/// The DER prefix of a BLS12-381 public key, as used for the IC root key.
+const DER_PREFIX_LENGTH: usize = 37;
+const BLS_KEY_LENGTH: usize = 96;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum VerifyError {
+    MalformedCertificate(String),
+    MalformedWitness(String),
+    InvalidSignature,
+    CanisterNotInRange,
+    CertifiedDataMismatch,
+    RecordNotCertified,
+    RecordMismatch,
+    ContentMismatch,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VerifyError::MalformedCertificate(e) => write!(f, "malformed certificate: {}", e),
+            VerifyError::MalformedWitness(e) => write!(f, "malformed witness: {}", e),
+            VerifyError::InvalidSignature => write!(f, "invalid certificate signature"),
+            VerifyError::CanisterNotInRange => {
+                write!(f, "canister is not in the range of the delegated subnet")
+            }
+            VerifyError::CertifiedDataMismatch => {
+                write!(f, "witness does not match the certified data")
+            }
+            VerifyError::RecordNotCertified => write!(f, "record is not in the witness"),
+            VerifyError::RecordMismatch => write!(f, "record does not match the certified leaf"),
+            VerifyError::ContentMismatch => write!(f, "content does not match the record hash"),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+/// What a valid receipt proves.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Verified {
+    /// The time of the certificate, i.e. when the record was known to be held
+    /// by the canister.  This is at or after the record's `created` time.
+    pub certified_at: Timestamp,
+}
+
+/// Verifies `receipt` for the canister `canister_id` (the raw principal
+/// bytes) against `root_key` (DER encoded, e.g. `IC_ROOT_KEY`).  If
+/// `content` is given it must hash to the record's hash.
+///
+/// The age of the certificate is deliberately not checked, receipts are
+/// meant to be verified long after they were issued.
+pub fn verify_receipt(
+    receipt: &Receipt,
+    canister_id: &[u8],
+    root_key: &[u8],
+    content: Option<&[u8]>,
+) -> Result<Verified, VerifyError> {
+    let certificate = Certificate::parse(&receipt.certificate)?;
+    certificate.verify(canister_id, root_key)?;
+    let certified_data = certificate
+        .tree
+        .lookup_leaf(&[b"canister", canister_id, b"certified_data"])
+        .ok_or_else(|| {
+            VerifyError::MalformedCertificate("no certified data for canister".to_string())
+        })?;
+    let certified_at = certificate
+        .tree
+        .lookup_leaf(&[b"time"])
+        .and_then(decode_leb128)
+        .ok_or_else(|| VerifyError::MalformedCertificate("missing time".to_string()))?;
+
+    let witness = HashTree::from_cbor(&receipt.witness).map_err(VerifyError::MalformedWitness)?;
+    if &witness.digest()[..] != certified_data {
+        return Err(VerifyError::CertifiedDataMismatch);
+    }
+    let record = &receipt.record;
+    let leaf = witness
+        .lookup_leaf(&[b"records", record.hash.as_bytes()])
+        .ok_or(VerifyError::RecordNotCertified)?;
+    // Hidden descriptions may be redacted with their salts, then only their
+    // hash is checked.
+    let mut attestations = vec![];
+    for a in record.attestations.iter() {
+        let mut hash = [0u8; 32];
+        hex::decode_to_slice(&a.description_hash, &mut hash)
+            .map_err(|_| VerifyError::RecordMismatch)?;
+        if !a.description.is_empty() {
+            let salt = hex::decode(&a.salt).map_err(|_| VerifyError::RecordMismatch)?;
+            if description_hash(&salt, &a.description) != hash {
+                return Err(VerifyError::RecordMismatch);
+            }
+        }
+        attestations.push(attestation_leaf_hash(&a.owner, a.created, &hash, a.hidden));
+    }
+    let expected = record_leaf_hash(
+        record.algorithm,
+        &record.owner,
+        &record.previous_owners,
+        record.hidden,
+        record.revealed,
+        record.takedown.as_ref().map(|t| t.at),
+        &attestations,
+    );
+    let original_owner = record
+        .previous_owners
+        .first()
+        .map_or(&record.owner, |p| &p.owner);
+    if leaf != &expected[..] || record.original_owner != *original_owner {
+        return Err(VerifyError::RecordMismatch);
+    }
+    // The priority date and description aren't in the leaf, they must be
+    // those of the first attestation, which is.
+    let consistent = match record.attestations.split_first() {
+        Some((priority, rest)) => {
+            priority.priority
+                && rest.iter().all(|a| !a.priority)
+                && record.created == priority.created
+                && record.description == priority.description
+        }
+        None => false,
+    };
+    if !consistent {
+        return Err(VerifyError::RecordMismatch);
+    }
+
+    if let Some(content) = content {
+        if hex::encode(record.algorithm.digest(content)) != record.hash {
+            return Err(VerifyError::ContentMismatch);
+        }
+    }
+    Ok(Verified { certified_at })
+}
+
+struct Certificate {
+    tree: HashTree,
+    signature: Vec<u8>,
+    delegation: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+impl Certificate {
+    fn parse(bytes: &[u8]) -> Result<Self, VerifyError> {
+        let malformed = |e: &str| VerifyError::MalformedCertificate(e.to_string());
+        let value: Value = serde_cbor::from_slice(bytes).map_err(|e| malformed(&e.to_string()))?;
+        let map = as_map(&value).ok_or_else(|| malformed("not a map"))?;
+        let tree = map
+            .get(&text("tree"))
+            .ok_or_else(|| malformed("missing tree"))
+            .and_then(|t| HashTree::from_value(t).map_err(|e| malformed(&e)))?;
+        let signature = map
+            .get(&text("signature"))
+            .and_then(as_bytes)
+            .ok_or_else(|| malformed("missing signature"))?
+            .to_vec();
+        let delegation = match map.get(&text("delegation")) {
+            None => None,
+            Some(d) => {
+                let d = as_map(d).ok_or_else(|| malformed("delegation is not a map"))?;
+                let subnet_id = d.get(&text("subnet_id")).and_then(as_bytes);
+                let certificate = d.get(&text("certificate")).and_then(as_bytes);
+                match (subnet_id, certificate) {
+                    (Some(s), Some(c)) => Some((s.to_vec(), c.to_vec())),
+                    _ => return Err(malformed("incomplete delegation")),
+                }
+            }
+        };
+        Ok(Certificate {
+            tree,
+            signature,
+            delegation,
+        })
+    }
+
+    fn verify(&self, canister_id: &[u8], root_key: &[u8]) -> Result<(), VerifyError> {
+        let key = match &self.delegation {
+            None => root_key.to_vec(),
+            Some((subnet_id, certificate)) => {
+                let delegation = Certificate::parse(certificate)?;
+                if delegation.delegation.is_some() {
+                    return Err(VerifyError::MalformedCertificate(
+                        "nested delegation".to_string(),
+                    ));
+                }
+                delegation.verify(canister_id, root_key)?;
+                let ranges = delegation
+                    .tree
+                    .lookup_leaf(&[b"subnet", subnet_id, b"canister_ranges"])
+                    .ok_or_else(|| {
+                        VerifyError::MalformedCertificate("missing canister ranges".to_string())
+                    })?;
+                let ranges: Vec<(serde_bytes::ByteBuf, serde_bytes::ByteBuf)> =
+                    serde_cbor::from_slice(ranges)
+                        .map_err(|e| VerifyError::MalformedCertificate(e.to_string()))?;
+                if !ranges
+                    .iter()
+                    .any(|(low, high)| &low[..] <= canister_id && canister_id <= &high[..])
+                {
+                    return Err(VerifyError::CanisterNotInRange);
+                }
+                delegation
+                    .tree
+                    .lookup_leaf(&[b"subnet", subnet_id, b"public_key"])
+                    .ok_or_else(|| {
+                        VerifyError::MalformedCertificate("missing subnet key".to_string())
+                    })?
+                    .to_vec()
+            }
+        };
+        if key.len() != DER_PREFIX_LENGTH + BLS_KEY_LENGTH {
+            return Err(VerifyError::MalformedCertificate(
+                "unexpected key length".to_string(),
+            ));
+        }
+        let mut message = b"\x0Dic-state-root".to_vec();
+        message.extend_from_slice(&self.tree.digest());
+        ic_verify_bls_signature::verify_bls_signature(
+            &self.signature,
+            &message,
+            &key[DER_PREFIX_LENGTH..],
+        )
+        .map_err(|_| VerifyError::InvalidSignature)
+    }
+}
+
+/// A hash tree as described in the IC interface specification.
+#[derive(Debug, PartialEq, Eq)]
+enum HashTree {
+    Empty,
+    Fork(Box<HashTree>, Box<HashTree>),
+    Labeled(Vec<u8>, Box<HashTree>),
+    Leaf(Vec<u8>),
+    Pruned([u8; 32]),
+}
+
+fn domain_sep(s: &str) -> sha2::Sha256 {
+    let mut hash = sha2::Sha256::new();
+    hash.update([s.len() as u8]);
+    hash.update(s.as_bytes());
+    hash
+}
+
+impl HashTree {
+    fn from_cbor(bytes: &[u8]) -> Result<Self, String> {
+        let value: Value = serde_cbor::from_slice(bytes).map_err(|e| e.to_string())?;
+        HashTree::from_value(&value)
+    }
+
+    fn from_value(value: &Value) -> Result<Self, String> {
+        let items = match value {
+            Value::Array(items) => items,
+            _ => return Err("hash tree node is not an array".to_string()),
+        };
+        let bytes = |i: usize| {
+            items
+                .get(i)
+                .and_then(as_bytes)
+                .ok_or_else(|| "expected bytes in hash tree node".to_string())
+        };
+        let subtree = |i: usize| {
+            items
+                .get(i)
+                .ok_or_else(|| "missing subtree".to_string())
+                .and_then(HashTree::from_value)
+                .map(Box::new)
+        };
+        match items.first() {
+            Some(Value::Integer(0)) => Ok(HashTree::Empty),
+            Some(Value::Integer(1)) => Ok(HashTree::Fork(subtree(1)?, subtree(2)?)),
+            Some(Value::Integer(2)) => Ok(HashTree::Labeled(bytes(1)?.to_vec(), subtree(2)?)),
+            Some(Value::Integer(3)) => Ok(HashTree::Leaf(bytes(1)?.to_vec())),
+            Some(Value::Integer(4)) => {
+                let hash = bytes(1)?;
+                if hash.len() != 32 {
+                    return Err("pruned hash is not 32 bytes".to_string());
+                }
+                let mut pruned = [0; 32];
+                pruned.copy_from_slice(hash);
+                Ok(HashTree::Pruned(pruned))
+            }
+            _ => Err("unknown hash tree node".to_string()),
+        }
+    }
+
+    fn digest(&self) -> [u8; 32] {
+        match self {
+            HashTree::Empty => domain_sep("ic-hashtree-empty").finalize().into(),
+            HashTree::Fork(l, r) => {
+                let mut hash = domain_sep("ic-hashtree-fork");
+                hash.update(l.digest());
+                hash.update(r.digest());
+                hash.finalize().into()
+            }
+            HashTree::Labeled(label, t) => {
+                let mut hash = domain_sep("ic-hashtree-labeled");
+                hash.update(label);
+                hash.update(t.digest());
+                hash.finalize().into()
+            }
+            HashTree::Leaf(data) => {
+                let mut hash = domain_sep("ic-hashtree-leaf");
+                hash.update(data);
+                hash.finalize().into()
+            }
+            HashTree::Pruned(hash) => *hash,
+        }
+    }
+
+    /// Finds the subtree labeled `label` among the children of this node,
+    /// looking through forks.
+    fn find_label(&self, label: &[u8]) -> Option<&HashTree> {
+        match self {
+            HashTree::Fork(l, r) => l.find_label(label).or_else(|| r.find_label(label)),
+            HashTree::Labeled(l, t) if &l[..] == label => Some(t),
+            _ => None,
+        }
+    }
+
+    fn lookup_leaf(&self, path: &[&[u8]]) -> Option<&[u8]> {
+        match path.split_first() {
+            None => match self {
+                HashTree::Leaf(data) => Some(data),
+                _ => None,
+            },
+            Some((label, rest)) => self.find_label(label)?.lookup_leaf(rest),
+        }
+    }
+}
+
+fn text(s: &str) -> Value {
+    Value::Text(s.to_string())
+}
+
+fn as_map(value: &Value) -> Option<&BTreeMap<Value, Value>> {
+    match value {
+        Value::Map(map) => Some(map),
+        _ => None,
+    }
+}
+
+fn as_bytes(value: &Value) -> Option<&[u8]> {
+    match value {
+        Value::Bytes(bytes) => Some(bytes),
+        _ => None,
+    }
+}
+
+fn decode_leb128(bytes: &[u8]) -> Option<u64> {
+    let mut result: u64 = 0;
+    for (i, b) in bytes.iter().enumerate() {
+        if i >= 10 {
+            return None;
+        }
+        result |= ((b & 0x7f) as u64) << (7 * i);
+        if b & 0x80 == 0 {
+            return Some(result);
+        }
+    }
+    None
+}
+
+/// The receipt verification for javascript, the receipt is Candid encoded.
+#[cfg(feature = "wasm")]
+#[wasm_bindgen::prelude::wasm_bindgen]
+pub fn verify_candid_receipt(
+    receipt: &[u8],
+    canister_id: &[u8],
+    content: Option<Vec<u8>>,
+) -> Result<u64, wasm_bindgen::JsValue> {
+    let receipt = candid::Decode!(receipt, Receipt)
+        .map_err(|e| wasm_bindgen::JsValue::from_str(&e.to_string()))?;
+    verify_receipt(&receipt, canister_id, IC_ROOT_KEY, content.as_deref())
+        .map(|v| v.certified_at)
+        .map_err(|e| wasm_bindgen::JsValue::from_str(&e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::Principal;
+    use dfnhack7_common::{AttestationResult, HashAlgorithm, RecordResult};
+
+    fn cbor(tree: &Value) -> Vec<u8> {
+        serde_cbor::to_vec(tree).unwrap()
+    }
+
+    fn node(tag: i128, rest: Vec<Value>) -> Value {
+        let mut items = vec![Value::Integer(tag)];
+        items.extend(rest);
+        Value::Array(items)
+    }
+
+    #[test]
+    fn check_leb128() {
+        assert_eq!(decode_leb128(&[0x00]), Some(0));
+        assert_eq!(decode_leb128(&[0xe5, 0x8e, 0x26]), Some(624485));
+        assert_eq!(decode_leb128(&[0x80]), None);
+    }
+
+    #[test]
+    fn check_hash_tree_lookup() {
+        let leaf = node(3, vec![Value::Bytes(b"leaf".to_vec())]);
+        let tree = node(
+            1,
+            vec![
+                node(4, vec![Value::Bytes(vec![0; 32])]),
+                node(
+                    2,
+                    vec![
+                        Value::Bytes(b"records".to_vec()),
+                        node(2, vec![Value::Bytes(b"abc".to_vec()), leaf]),
+                    ],
+                ),
+            ],
+        );
+        let tree = HashTree::from_cbor(&cbor(&tree)).unwrap();
+        assert_eq!(tree.lookup_leaf(&[b"records", b"abc"]), Some(&b"leaf"[..]));
+        assert_eq!(tree.lookup_leaf(&[b"records", b"abd"]), None);
+        assert_eq!(tree.lookup_leaf(&[b"http_assets"]), None);
+        assert!(HashTree::from_cbor(&cbor(&node(4, vec![Value::Bytes(vec![0; 3])]))).is_err());
+    }
+
+    const CANISTER_ID: &[u8] = &[0, 0, 0, 0, 0, 0, 0, 1, 1, 1];
+
+    fn label(name: &[u8], tree: Value) -> Value {
+        node(2, vec![Value::Bytes(name.to_vec()), tree])
+    }
+
+    fn leaf(data: &[u8]) -> Value {
+        node(3, vec![Value::Bytes(data.to_vec())])
+    }
+
+    fn digest(tree: &Value) -> [u8; 32] {
+        HashTree::from_value(tree).unwrap().digest()
+    }
+
+    fn attestation(owner: Principal, created: Timestamp, description: &str) -> AttestationResult {
+        let salt = [created as u8; 32];
+        AttestationResult {
+            owner,
+            created,
+            description: description.to_string(),
+            description_hash: hex::encode(description_hash(&salt, description)),
+            salt: hex::encode(salt),
+            hidden: false,
+            priority: false,
+        }
+    }
+
+    /// A receipt for "hello" attested twice, and the DER root key of the
+    /// secret key `secret` which signed its certificate.
+    fn fixture(secret: u64) -> (Receipt, Vec<u8>) {
+        use bls12_381::hash_to_curve::{ExpandMsgXmd, HashToCurve};
+        use bls12_381::{G1Projective, G2Projective, Scalar};
+        use pairing::group::Curve;
+
+        let owner = Principal::from_slice(&[7]);
+        let mut attestations = vec![
+            attestation(owner, 10, "minutes"),
+            attestation(Principal::from_slice(&[8]), 20, "copy"),
+        ];
+        attestations[0].priority = true;
+        let record = RecordResult {
+            hash: hex::encode(sha2::Sha256::digest(b"hello")),
+            algorithm: HashAlgorithm::Sha256,
+            digests: vec![],
+            owner,
+            has_datum: true,
+            description: "minutes".to_string(),
+            hidden: false,
+            created: 10,
+            attestations,
+            grants: vec![],
+            reveal_at: None,
+            revealed: None,
+            original_owner: owner,
+            previous_owners: vec![],
+            transfer_to: None,
+            takedown: None,
+        };
+        let attestations = record
+            .attestations
+            .iter()
+            .map(|a| {
+                let mut hash = [0; 32];
+                hex::decode_to_slice(&a.description_hash, &mut hash).unwrap();
+                attestation_leaf_hash(&a.owner, a.created, &hash, a.hidden)
+            })
+            .collect::<Vec<_>>();
+        let record_leaf = record_leaf_hash(
+            record.algorithm,
+            &record.owner,
+            &record.previous_owners,
+            record.hidden,
+            record.revealed,
+            None,
+            &attestations,
+        );
+        let witness = node(
+            1,
+            vec![
+                label(b"http_assets", node(4, vec![Value::Bytes(vec![0; 32])])),
+                label(
+                    b"records",
+                    label(record.hash.as_bytes(), leaf(&record_leaf)),
+                ),
+            ],
+        );
+        let tree = node(
+            1,
+            vec![
+                label(
+                    b"canister",
+                    label(
+                        CANISTER_ID,
+                        label(b"certified_data", leaf(&digest(&witness))),
+                    ),
+                ),
+                label(b"time", leaf(&[0xe5, 0x8e, 0x26])),
+            ],
+        );
+
+        let secret = Scalar::from(secret);
+        let mut message = b"\x0Dic-state-root".to_vec();
+        message.extend_from_slice(&digest(&tree));
+        let point = <G1Projective as HashToCurve<ExpandMsgXmd<sha2::Sha256>>>::hash_to_curve(
+            &message,
+            b"BLS_SIG_BLS12381G1_XMD:SHA-256_SSWU_RO_NUL_",
+        );
+        let signature = (point * secret).to_affine().to_compressed();
+        let mut root_key = IC_ROOT_KEY[..DER_PREFIX_LENGTH].to_vec();
+        root_key.extend_from_slice(
+            &(G2Projective::generator() * secret)
+                .to_affine()
+                .to_compressed(),
+        );
+
+        let mut certificate = BTreeMap::new();
+        certificate.insert(text("tree"), tree);
+        certificate.insert(text("signature"), Value::Bytes(signature.to_vec()));
+        let receipt = Receipt {
+            record,
+            certificate: serde_bytes::ByteBuf::from(cbor(&Value::Map(certificate))),
+            witness: serde_bytes::ByteBuf::from(cbor(&witness)),
+        };
+        (receipt, root_key)
+    }
+
+    #[test]
+    fn check_receipt() {
+        let (receipt, root_key) = fixture(42);
+        let verify = |receipt: &Receipt| verify_receipt(receipt, CANISTER_ID, &root_key, None);
+        assert_eq!(
+            verify(&receipt),
+            Ok(Verified {
+                certified_at: 624485
+            })
+        );
+        assert_eq!(
+            verify_receipt(&receipt, CANISTER_ID, &root_key, Some(b"hello")),
+            Ok(Verified {
+                certified_at: 624485
+            })
+        );
+        assert_eq!(
+            verify_receipt(&receipt, CANISTER_ID, &root_key, Some(b"hullo")),
+            Err(VerifyError::ContentMismatch)
+        );
+        // Signed by another key.
+        let (_, other_key) = fixture(43);
+        assert_eq!(
+            verify_receipt(&receipt, CANISTER_ID, &other_key, None),
+            Err(VerifyError::InvalidSignature)
+        );
+        assert!(matches!(
+            verify_receipt(&receipt, &[1], &root_key, None),
+            Err(VerifyError::MalformedCertificate(_))
+        ));
+
+        let tampered = |f: &dyn Fn(&mut RecordResult)| {
+            let mut receipt = receipt.clone();
+            f(&mut receipt.record);
+            verify(&receipt)
+        };
+        let mismatch = Err(VerifyError::RecordMismatch);
+        assert_eq!(tampered(&|r| r.created = 5), mismatch);
+        assert_eq!(tampered(&|r| r.description = "copy".to_string()), mismatch);
+        assert_eq!(tampered(&|r| r.attestations[1].priority = true), mismatch);
+        assert_eq!(tampered(&|r| r.attestations[0].priority = false), mismatch);
+        assert_eq!(tampered(&|r| r.attestations[0].created = 5), mismatch);
+        assert_eq!(
+            tampered(&|r| r.attestations[1].description = "forged".to_string()),
+            mismatch
+        );
+        assert_eq!(
+            tampered(&|r| r.owner = Principal::from_slice(&[8])),
+            mismatch
+        );
+        assert_eq!(tampered(&|r| r.attestations.clear()), mismatch);
+        assert_eq!(
+            tampered(&|r| r.hash = hex::encode(sha2::Sha256::digest(b"hullo"))),
+            Err(VerifyError::RecordNotCertified)
+        );
+        assert_eq!(tampered(&|r| r.attestations[1].salt.clear()), mismatch);
+        assert_eq!(
+            tampered(&|r| r.attestations[1].description_hash =
+                hex::encode(description_hash(b"", "copy"))),
+            mismatch
+        );
+        // A redacted description is checked against its hash only.
+        assert_eq!(
+            tampered(&|r| {
+                r.description.clear();
+                r.attestations[0].description.clear();
+                r.attestations[0].salt.clear();
+            }),
+            Ok(Verified {
+                certified_at: 624485
+            })
+        );
+    }
+
+    #[test]
+    fn check_empty_digest() {
+        assert_eq!(
+            hex::encode(HashTree::Empty.digest()),
+            "4e3ed35c4e2d1ee89996483fb6260a64cffb6c47dbab216e7930e82f8190d120"
+        );
+    }
+}